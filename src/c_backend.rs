@@ -0,0 +1,198 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fmt::Write;
+
+use crate::interpreter::{Eof, Machine};
+use crate::ir::Node;
+use crate::language::CellWidth;
+
+/// Writes a program out as a standalone C program for the given machine.
+pub fn generate(program: &[Node], machine: &Machine) -> String {
+    // C has no unbounded integers, so the widest one has to do.
+    let cell = match machine.cell_width {
+        CellWidth::Bits8 => "uint8_t",
+        CellWidth::Bits16 => "uint16_t",
+        CellWidth::Bits32 => "uint32_t",
+        CellWidth::Unbounded => "uint64_t",
+    };
+
+    // What to multiply in, which must be unsigned and at least as wide as `unsigned int`.
+    let wide = match machine.cell_width {
+        CellWidth::Bits8 | CellWidth::Bits16 | CellWidth::Bits32 => "uint32_t",
+        CellWidth::Unbounded => "uint64_t",
+    };
+
+    let mut c = String::new();
+
+    let _ = writeln!(c, "#include <stdint.h>");
+    let _ = writeln!(c, "#include <stdio.h>");
+    let _ = writeln!(c);
+    let _ = writeln!(c, "#define TAPE_LENGTH {}", machine.tape_length);
+    let _ = writeln!(c);
+    let _ = writeln!(c, "typedef {} cell;", cell);
+    let _ = writeln!(c, "typedef {} wide;", wide);
+    let _ = writeln!(c);
+    let _ = writeln!(c, "static cell tape[TAPE_LENGTH];");
+    let _ = writeln!(c);
+    let _ = writeln!(c, "int main(void) {{");
+    let _ = writeln!(c, "    cell *p = tape;");
+
+    if uses_input(program) {
+        let _ = writeln!(c, "    int c;");
+    }
+
+    let _ = writeln!(c);
+
+    generate_nodes(program, machine, 1, &mut c);
+
+    let _ = writeln!(c);
+    let _ = writeln!(c, "    return 0;");
+    let _ = writeln!(c, "}}");
+
+    c
+}
+
+fn generate_nodes(nodes: &[Node], machine: &Machine, depth: usize, c: &mut String) {
+    let indent = "    ".repeat(depth);
+
+    for node in nodes {
+        let _ = match node {
+            Node::Add { offset, amount } if *amount < 0 => {
+                writeln!(c, "{}p[{}] -= {};", indent, offset, amount.unsigned_abs())
+            }
+            Node::Add { offset, amount } => writeln!(c, "{}p[{}] += {};", indent, offset, amount),
+            Node::Move(amount) if *amount < 0 => {
+                writeln!(c, "{}p -= {};", indent, amount.unsigned_abs())
+            }
+            Node::Move(amount) => writeln!(c, "{}p += {};", indent, amount),
+            Node::Read => {
+                let _ = writeln!(c, "{}c = getchar();", indent);
+
+                match machine.eof {
+                    Eof::Zero => writeln!(c, "{}p[0] = c == EOF ? 0 : (cell)c;", indent),
+                    Eof::Unchanged => writeln!(c, "{}if (c != EOF) p[0] = (cell)c;", indent),
+                    Eof::MinusOne => writeln!(c, "{}p[0] = c == EOF ? (cell)-1 : (cell)c;", indent),
+                }
+            }
+            Node::Write => writeln!(c, "{}putchar((unsigned char)p[0]);", indent),
+            Node::Loop(body) => {
+                let _ = writeln!(c, "{}while (p[0]) {{", indent);
+                generate_nodes(body, machine, depth + 1, c);
+                writeln!(c, "{}}}", indent)
+            }
+            Node::Clear => writeln!(c, "{}p[0] = 0;", indent),
+            Node::MultiplyAdd { offset, factor } => {
+                // Unsigned arithmetic wraps, so a negative factor works out the same as in Brainfuck,
+                // but narrower cells would be promoted to a signed `int` that could overflow.
+                let _ = writeln!(
+                    c,
+                    "{}p[{}] += (cell)((wide)p[0] * (wide)(cell){});",
+                    indent, offset, factor
+                );
+                writeln!(c, "{}p[0] = 0;", indent)
+            }
+        };
+    }
+}
+
+fn uses_input(nodes: &[Node]) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Read => true,
+        Node::Loop(body) => uses_input(body),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(cell_width: CellWidth) -> Machine {
+        Machine {
+            cell_width,
+            wrapping: cell_width != CellWidth::Unbounded,
+            tape_length: 100,
+            eof: Eof::Zero,
+        }
+    }
+
+    fn multiply_program() -> Vec<Node> {
+        vec![
+            Node::Add {
+                offset: 0,
+                amount: 3,
+            },
+            Node::MultiplyAdd {
+                offset: 1,
+                factor: -2,
+            },
+            Node::Move(1),
+            Node::Write,
+        ]
+    }
+
+    #[test]
+    fn cell_types() {
+        for (cell_width, cell, wide) in [
+            (CellWidth::Bits8, "uint8_t", "uint32_t"),
+            (CellWidth::Bits16, "uint16_t", "uint32_t"),
+            (CellWidth::Bits32, "uint32_t", "uint32_t"),
+            (CellWidth::Unbounded, "uint64_t", "uint64_t"),
+        ] {
+            let c = generate(&multiply_program(), &machine(cell_width));
+
+            assert!(c.contains(&format!("typedef {} cell;", cell)), "{}", c);
+            assert!(c.contains(&format!("typedef {} wide;", wide)), "{}", c);
+            assert!(c.contains("#define TAPE_LENGTH 100"), "{}", c);
+        }
+    }
+
+    #[test]
+    fn multiplications_stay_unsigned() {
+        for cell_width in [CellWidth::Bits16, CellWidth::Bits32, CellWidth::Unbounded] {
+            let c = generate(&multiply_program(), &machine(cell_width));
+
+            assert!(
+                c.contains("    p[1] += (cell)((wide)p[0] * (wide)(cell)-2);\n    p[0] = 0;\n"),
+                "{}",
+                c
+            );
+        }
+    }
+
+    #[test]
+    fn nodes() {
+        let program = vec![
+            Node::Add {
+                offset: 0,
+                amount: -4,
+            },
+            Node::Move(-2),
+            Node::Loop(vec![Node::Read, Node::Clear]),
+            Node::Write,
+        ];
+
+        let c = generate(&program, &machine(CellWidth::Bits16));
+
+        assert!(c.contains("    int c;\n"), "{}", c);
+        assert!(c.contains("    p[0] -= 4;\n    p -= 2;\n"), "{}", c);
+        assert!(
+            c.contains("    while (p[0]) {\n        c = getchar();\n"),
+            "{}",
+            c
+        );
+        assert!(
+            c.contains("        p[0] = c == EOF ? 0 : (cell)c;\n        p[0] = 0;\n    }\n"),
+            "{}",
+            c
+        );
+        assert!(c.contains("    putchar((unsigned char)p[0]);\n"), "{}", c);
+
+        // Without any input, there's nothing to read it into.
+        assert!(!generate(&multiply_program(), &machine(CellWidth::Bits16)).contains("int c;"));
+    }
+}
@@ -7,82 +7,452 @@
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufWriter, Write};
-use std::iter;
 
+use crate::ir::{self, Node};
+use crate::knowledge::{Fact, Knowledge};
 use crate::language::*;
+use crate::layout::{self, Clear, Layout, Lifetime};
+use crate::logger;
+use crate::optimizer;
+use crate::report::{MemoryEntry, MemoryMap, Reports, SizeEntry, SizeReport};
+use crate::verifier;
+
+/// How deeply macros may expand inside of one another.
+/// Macros can call each other in any order, so recursion has to be cut off somewhere.
+const MAX_EXPANSION_DEPTH: usize = 256;
+
+/// Separates a variable name from the expansion that owns it.
+/// Identifiers can never contain a quote, so hygienic names cannot collide with user names.
+const HYGIENE_SEPARATOR: char = '\'';
+
+/// How far from the current cell to look for a free cell to multiply with.
+const SCRATCH_DISTANCE: usize = 8;
+
+/// The tape length that most interpreters give programs.
+const DEFAULT_TAPE_LENGTH: usize = 30_000;
+
+#[derive(Copy, Clone, Debug)]
+struct Allocation {
+    location: usize,
+    size: usize,
+    lifetime: Option<usize>,
+    /// Where the allocation is recorded for the memory map.
+    placement: usize,
+}
 
-const KNOWN_ZERO_CAPACITY: usize = 32;
+/// Who allocated a variable, and which cells it was given, for the memory map.
+struct Placement {
+    name: String,
+    scope: String,
+    lifetime: Option<usize>,
+}
 
-pub struct Compiler {
-    head: usize,
-    next_allocation: usize,
+/// Settings for the target and for which optimizations run.
+#[derive(Copy, Clone, Debug)]
+pub struct Options {
+    /// How wide the target's cells are.
+    pub cell_width: CellWidth,
+    /// Whether cells wrap around when they go past either end of their range.
+    /// Unbounded cells never do.
+    pub wrapping: bool,
+    /// How many cells the target's tape has, which the output is checked against.
+    pub tape_length: usize,
+
+    /// Skips clears and loops on cells that are known to be zero.
+    pub track_zeros: bool,
+    /// Steps from a cell's known value instead of clearing it first.
+    pub track_values: bool,
+    /// Builds large constants with multiplication loops.
+    pub generate_constants: bool,
+    /// Cleans up the finished Brainfuck without changing what it reads or writes.
+    pub peephole: bool,
+    /// Shares cells between variables whose lifetimes don't overlap.
+    pub pack_cells: bool,
+    /// Places cells that are accessed one after another next to each other.
+    pub arrange_cells: bool,
+}
 
-    allocation_stack: Vec<HashMap<String, usize>>,
-    pool_stack: Vec<HashMap<String, usize>>,
+impl Options {
+    /// The settings for an optimization level from 0 (everything off) to 3 (everything on).
+    pub fn level(level: usize) -> Self {
+        Self {
+            cell_width: CellWidth::Bits8,
+            wrapping: true,
+            tape_length: DEFAULT_TAPE_LENGTH,
+
+            track_zeros: level >= 1,
+            track_values: level >= 2,
+            generate_constants: level >= 2,
+            peephole: level >= 3,
+            pack_cells: level >= 3,
+            arrange_cells: level >= 3,
+        }
+    }
+}
 
-    marker: usize,
-    zero_horizon: usize,
-    known_zeros: HashSet<usize>,
+impl Default for Options {
+    fn default() -> Self {
+        Self::level(2)
+    }
+}
 
-    brainfuck: Vec<Brainfuck>,
+/// A compiled program, both as nodes for other backends and as the Brainfuck they lower to.
+pub struct Output {
+    pub program: Vec<Node>,
+    pub brainfuck: Vec<Brainfuck>,
+    pub reports: Reports,
 }
 
-impl Compiler {
-    pub fn new() -> Self {
-        let mut known_zeros = HashSet::with_capacity(KNOWN_ZERO_CAPACITY);
+/// What opened an allocation marker, which decides what may close it.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Marker {
+    /// A `{ ... }` block, closed by its own end.
+    Block,
+    /// A `__mark`, closed by a `__restore`.
+    Mark,
+}
 
-        for i in 0..KNOWN_ZERO_CAPACITY {
-            known_zeros.insert(i);
-        }
+/// A loop whose body is currently being built.
+struct LoopFrame {
+    ordinal: usize,
+    entry: Knowledge,
+    entry_stamp: usize,
+    /// Cells whose values from before the loop decided what the body emits.
+    relied: HashSet<usize>,
+    /// Cells that the body may change.
+    touched: HashSet<usize>,
+    touched_all: bool,
+}
+
+/// Cells that a loop's body was found to change,
+/// and which therefore can't be trusted on entry to the loop.
+#[derive(Default)]
+struct Clobber {
+    cells: HashSet<usize>,
+    all: bool,
+}
+
+/// A way of changing the current cell's value into a constant.
+#[derive(Copy, Clone, Debug)]
+enum Constant {
+    Steps {
+        up: usize,
+        down: usize,
+    },
+    /// Adds `outer * inner` using a loop on a zeroed scratch cell,
+    /// then steps the rest of the way.
+    Product {
+        scratch: usize,
+        outer: usize,
+        inner: usize,
+        negative: bool,
+        remainder: usize,
+        /// Whether the product went past the constant and the remainder steps back.
+        overshot: bool,
+    },
+}
+
+/// A single expansion of a macro body.
+///
+/// Variables named inside a macro body belong to the expansion that named them,
+/// unless the macro exports them to its caller.
+struct Expansion<'a> {
+    id: usize,
+    name: &'a str,
+    values: ValueList,
+    exports: &'a [String],
+    parent: Option<&'a Expansion<'a>>,
+}
+
+pub struct Compiler {
+    head: usize,
+    /// Where the emitted Brainfuck leaves the head. Moves are only emitted once something
+    /// happens at the new cell, so moving to a loop that is skipped leaves no trace.
+    emitted_head: usize,
+    next_allocation: usize,
+    next_expansion: usize,
+    expansion_depth: usize,
+    instruction_offset: usize,
+
+    allocation_stack: Vec<HashMap<String, Allocation>>,
+    pool_stack: Vec<HashMap<String, Allocation>>,
+    unreserved: Vec<String>,
+    /// Cells given back by `__free`, kept sorted by location and coalesced.
+    free_list: Vec<Allocation>,
+
+    /// What opened every marker still open, and the allocation to rewind to.
+    /// Each marker pairs with the allocation stack pushed alongside it.
+    markers: Vec<(Marker, usize)>,
+
+    knowledge: Knowledge,
+    stamp: usize,
+    loop_frames: Vec<LoopFrame>,
+    next_loop: usize,
+    /// How many loops deep the compiler is into a loop that can never run.
+    dead_loops: usize,
+    /// Clears that were due inside of a loop that can never run.
+    deferred_clears: Vec<(usize, usize)>,
+    /// Loops whose bodies undermined what was assumed on entry, from the previous attempt.
+    loop_clobbers: HashMap<usize, Clobber>,
+    /// Whether this attempt relied on something that a loop later changed.
+    unstable: bool,
+
+    options: Options,
+    /// Cells chosen by a previous pass for each lifetime.
+    layout: Option<Layout>,
+    /// Whether every cell access goes through a variable, so cells can be moved around safely.
+    relocatable: bool,
+    /// How many cells from the start of the tape the program reaches.
+    peak: usize,
+    lifetimes: Vec<Lifetime>,
+    placements: Vec<Placement>,
+    /// The names of the macros being expanded, outermost first.
+    expanding: Vec<String>,
+    /// How much output each macro is responsible for, by name.
+    sizes: HashMap<String, SizeEntry>,
+    /// How much output came from outside of any macro.
+    top_level_size: usize,
+    /// What the move to the head's cell will cost once it's emitted,
+    /// and the macros being expanded when the head was last moved, who are charged for it.
+    move_cost: usize,
+    move_owner: Vec<String>,
+    /// Every variable access as a lifetime index and an offset into its cells.
+    accesses: Vec<(usize, usize)>,
+    /// Each open loop's starting instruction and the cell that its `[` is on.
+    open_loops: Vec<(usize, usize)>,
+    loops: Vec<(usize, usize)>,
+
+    /// The nodes of the program, followed by the bodies of each loop that is still open.
+    nodes: Vec<Vec<Node>>,
+}
 
-        let mut allocation_stack = Vec::new();
-        allocation_stack.push(HashMap::new());
+impl Compiler {
+    pub fn new(options: Options) -> Self {
+        let allocation_stack = vec![HashMap::new()];
 
         Self {
             head: 0,
+            emitted_head: 0,
             next_allocation: 0,
+            next_expansion: 1,
+            expansion_depth: 0,
+            instruction_offset: 0,
 
             allocation_stack,
             pool_stack: Vec::new(),
-
-            marker: 0,
-            zero_horizon: 0,
-            known_zeros,
-
-            brainfuck: Vec::new(),
+            unreserved: Vec::new(),
+            free_list: Vec::new(),
+
+            markers: Vec::new(),
+
+            knowledge: Knowledge::new(),
+            stamp: 0,
+            loop_frames: Vec::new(),
+            next_loop: 0,
+            dead_loops: 0,
+            deferred_clears: Vec::new(),
+            loop_clobbers: HashMap::new(),
+            unstable: false,
+
+            options,
+            layout: None,
+            relocatable: true,
+            peak: 0,
+            lifetimes: Vec::new(),
+            placements: Vec::new(),
+            expanding: Vec::new(),
+            sizes: HashMap::new(),
+            top_level_size: 0,
+            move_cost: 0,
+            move_owner: Vec::new(),
+            accesses: Vec::new(),
+            open_loops: Vec::new(),
+            loops: Vec::new(),
+
+            nodes: vec![Vec::new()],
         }
     }
 
+    /// Compiles the program into a Brainfuck file, returning reports on where every variable went
+    /// and which macros the output came from.
     pub fn compile(
-        mut self,
+        self,
         top_level: Vec<Instruction>,
         macros: HashMap<String, Macro>,
         filename: &str,
-    ) -> Result<(), ()> {
-        match self.build_instructions(&top_level, &macros, None) {
-            Ok(_) => match self.save(filename) {
-                Ok(()) => Ok(()),
-                Err(_) => panic!("WOO!"),
-            },
+    ) -> Result<Reports, ()> {
+        let output = self.build(top_level, macros)?;
+
+        match save(&output.brainfuck, filename) {
+            Ok(()) => Ok(output.reports),
             Err(_) => panic!("WOO!"),
         }
     }
 
+    /// Compiles the program without saving it anywhere.
+    pub fn build(
+        self,
+        top_level: Vec<Instruction>,
+        macros: HashMap<String, Macro>,
+    ) -> Result<Output, ()> {
+        let first = self.build_program(&top_level, &macros, None);
+
+        // The expansion never depends on where variables live,
+        // so later passes see the exact same lifetimes in the same order.
+        let mut compiler = if first.options.pack_cells && first.relocatable {
+            let layout = layout::pack(&first.lifetimes, &first.loops);
+            first.build_program(&top_level, &macros, Some(layout))
+        } else {
+            first
+        };
+
+        if compiler.options.arrange_cells && compiler.relocatable {
+            let layout = layout::arrange(
+                &compiler.lifetimes,
+                &compiler.accesses,
+                compiler.layout.as_ref(),
+            );
+            let arranged = compiler.build_program(&top_level, &macros, Some(layout));
+
+            let before = compiler.pointer_moves();
+            let after = arranged.pointer_moves();
+
+            if after < before {
+                logger::info(format!(
+                    "Arranging cells saved {} pointer moves.",
+                    before - after
+                ));
+                compiler = arranged;
+            } else {
+                logger::info("Arranging cells saved no pointer moves.");
+            }
+        }
+
+        let options = compiler.options;
+
+        // Packing may well bring a program that ran off the end of the tape back onto it.
+        if compiler.peak > options.tape_length {
+            panic!(
+                "WOO: cell {} is past the end of the tape",
+                compiler.peak - 1
+            )
+        }
+
+        logger::info(format!(
+            "Peak tape usage: {} of {} cells.",
+            compiler.peak, options.tape_length
+        ));
+
+        let reports = Reports {
+            memory_map: compiler.memory_map(),
+            sizes: compiler.size_report(),
+        };
+
+        let mut program = compiler.program();
+
+        if options.peephole {
+            program = optimizer::optimize(program, options.wrapping);
+        }
+
+        let brainfuck = ir::lower(&program);
+        let mut problems = 0;
+
+        for diagnostic in verifier::verify(&brainfuck, options.tape_length) {
+            if diagnostic.is_fatal() {
+                logger::error(diagnostic);
+                problems += 1;
+            } else {
+                logger::warning(diagnostic);
+            }
+        }
+
+        if problems > 0 {
+            panic!("WOO: {} problems with the output", problems)
+        }
+
+        Ok(Output {
+            program,
+            brainfuck,
+            reports,
+        })
+    }
+
+    /// Builds the whole program with a fresh compiler using the same settings.
+    ///
+    /// A loop's body is built before it's known what the body changes, so anything assumed
+    /// on entry might not hold on the next iteration. When that happens, the program is rebuilt
+    /// without those assumptions; each attempt only ever assumes less, so this settles.
+    fn build_program(
+        &self,
+        top_level: &Vec<Instruction>,
+        macros: &HashMap<String, Macro>,
+        layout: Option<Layout>,
+    ) -> Compiler {
+        let mut loop_clobbers = HashMap::new();
+
+        loop {
+            let mut compiler = Compiler::new(self.options);
+
+            compiler.layout = layout.clone();
+            compiler.loop_clobbers = loop_clobbers;
+
+            if compiler
+                .build_instructions(top_level, macros, None)
+                .is_err()
+            {
+                panic!("WOO!")
+            }
+
+            if let Some(&(start, _)) = compiler.open_loops.last() {
+                panic!("WOO: loop at instruction {} is never closed", start)
+            }
+
+            if !compiler.markers.is_empty() {
+                panic!("WOO: __mark is never restored")
+            }
+
+            if compiler.unstable {
+                loop_clobbers = compiler.loop_clobbers;
+            } else {
+                return compiler;
+            }
+        }
+    }
+
     fn build_instructions(
         &mut self,
         instructions: &Vec<Instruction>,
         macros: &HashMap<String, Macro>,
-        values: Option<&ValueList>,
+        expansion: Option<&Expansion>,
     ) -> Result<(), ()> {
         for instruction in instructions {
             match instruction {
                 Instruction::Builtin(builtin) => {
-                    if let Err(_) = self.build_builtin(builtin, values) {
+                    if self.build_builtin(builtin, expansion).is_err() {
+                        panic!("WOO!")
+                    }
+                }
+                Instruction::Raw(raw) => {
+                    if self.build_raw(raw).is_err() {
                         panic!("WOO!")
                     }
                 }
+                Instruction::Block(block) => {
+                    self.mark(Marker::Block);
+
+                    if self.build_instructions(block, macros, expansion).is_err() {
+                        panic!("WOO!")
+                    }
+
+                    if self.restore(Marker::Block).is_err() {
+                        panic!("WOO: __mark is never restored inside of a block")
+                    }
+                }
                 Instruction::Macro(macro_name, macro_values) => {
-                    if let Err(_) = self.build_macro(macro_name, macros, macro_values, values) {
+                    if self
+                        .build_macro(macro_name, macros, macro_values, expansion)
+                        .is_err()
+                    {
                         panic!("WOO!")
                     }
                 }
@@ -97,18 +467,53 @@ fn build_macro(
         macro_name: &str,
         macros: &HashMap<String, Macro>,
         macro_values: &ValueList,
-        passed_values: Option<&ValueList>,
+        caller: Option<&Expansion>,
     ) -> Result<(), ()> {
         if let Some(macro_data) = macros.get(macro_name) {
-            match resolve_values(macro_values, passed_values) {
+            if macro_values.length != macro_data.parameter_count {
+                panic!("WOO: {}", macro_name)
+            }
+
+            if self.expansion_depth >= MAX_EXPANSION_DEPTH {
+                panic!("WOO: {}", macro_name)
+            }
+
+            match resolve_values(macro_values, caller) {
                 Ok(resolved_values) => {
-                    if let Err(_) = self.build_instructions(
-                        &macro_data.instructions,
-                        macros,
-                        Some(&resolved_values),
-                    ) {
+                    let expansion = Expansion {
+                        id: self.next_expansion,
+                        name: macro_name,
+                        values: resolved_values,
+                        exports: &macro_data.exports,
+                        parent: caller,
+                    };
+
+                    let markers = self.markers.len();
+
+                    self.next_expansion += 1;
+                    self.expansion_depth += 1;
+                    self.expanding.push(macro_name.to_owned());
+
+                    // Nothing inside of a loop that never runs is emitted, so no call is counted.
+                    if self.dead_loops == 0 {
+                        let size = self.sizes.entry(macro_name.to_owned()).or_default();
+                        size.name = macro_name.to_owned();
+                        size.calls += 1;
+                    }
+
+                    if self
+                        .build_instructions(&macro_data.instructions, macros, Some(&expansion))
+                        .is_err()
+                    {
                         panic!("WOO!")
                     }
+
+                    if self.markers.len() != markers {
+                        panic!("WOO: unbalanced __mark in {}", macro_name)
+                    }
+
+                    self.expanding.pop();
+                    self.expansion_depth -= 1;
                 }
                 Err(_) => panic!("WOO!"),
             }
@@ -119,13 +524,19 @@ fn build_macro(
         Ok(())
     }
 
-    fn build_builtin(&mut self, builtin: &Builtin, values: Option<&ValueList>) -> Result<(), ()> {
+    fn build_builtin(
+        &mut self,
+        builtin: &Builtin,
+        expansion: Option<&Expansion>,
+    ) -> Result<(), ()> {
+        self.instruction_offset += 1;
+
         match builtin {
             Builtin::Allocate(value) => {
-                let value = resolve_value(value, values).unwrap();
+                let value = resolve_value(value, expansion).unwrap();
 
                 if let Value::Variable(variable) = value {
-                    match self.allocate(&variable) {
+                    match self.allocate(&variable, expansion) {
                         Ok(_) => {}
                         Err(_) => panic!("WOO!"),
                     }
@@ -135,8 +546,21 @@ fn build_builtin(&mut self, builtin: &Builtin, values: Option<&ValueList>) -> Re
                     unreachable!()
                 }
             }
+            Builtin::Free(value) => {
+                let value = resolve_value(value, expansion).unwrap();
+
+                if let Value::Variable(variable) = value {
+                    if self.free(&variable).is_err() {
+                        panic!("WOO: {}", variable)
+                    }
+                } else {
+                    // SAFETY: The value has already been resolved,
+                    // and the parser ensures that it's the correct type.
+                    unreachable!()
+                }
+            }
             Builtin::Reserve(value) => {
-                let value = resolve_value(value, values).unwrap();
+                let value = resolve_value(value, expansion).unwrap();
 
                 if let Value::Literal(literal) = value {
                     self.reserve(literal);
@@ -147,7 +571,7 @@ fn build_builtin(&mut self, builtin: &Builtin, values: Option<&ValueList>) -> Re
                 }
             }
             Builtin::Set(value) => {
-                let value = resolve_value(value, values).unwrap();
+                let value = resolve_value(value, expansion).unwrap();
 
                 if let Value::Literal(literal) = value {
                     self.set(literal);
@@ -158,19 +582,32 @@ fn build_builtin(&mut self, builtin: &Builtin, values: Option<&ValueList>) -> Re
                 }
             }
             Builtin::Move(value) => {
-                let value = resolve_value(value, values).unwrap();
+                let value = resolve_value(value, expansion).unwrap();
 
                 match value {
                     Value::Literal(literal) => {
+                        self.relocatable = false;
                         self.move_to(literal);
                     }
                     Value::Variable(variable) => {
-                        if let Some(location) = self.variable_location(&variable) {
+                        if let Some(location) = self.variable_location(&variable, 0) {
                             self.move_to(location);
                         } else {
                             panic!("WOO!")
                         }
                     }
+                    Value::Offset(base, offset) => {
+                        if let Value::Variable(variable) = *base {
+                            if let Some(location) = self.variable_location(&variable, offset) {
+                                self.move_to(location);
+                            } else {
+                                panic!("WOO!")
+                            }
+                        } else {
+                            // SAFETY: Resolving folds offsets from literals into the literal itself.
+                            unreachable!()
+                        }
+                    }
                     Value::Parameter(_) => {
                         // SAFETY: The value has already been resolved,
                         // and the parser ensures that it's the correct type.
@@ -179,25 +616,40 @@ fn build_builtin(&mut self, builtin: &Builtin, values: Option<&ValueList>) -> Re
                 }
             }
             Builtin::Mark => {
-                self.mark();
+                self.mark(Marker::Mark);
             }
             Builtin::Restore => {
-                self.restore();
+                if self.restore(Marker::Mark).is_err() {
+                    panic!("WOO: unbalanced __restore")
+                }
             }
             Builtin::Hint(value) => {
-                let value = resolve_value(value, values).unwrap();
+                let value = resolve_value(value, expansion).unwrap();
 
                 match value {
                     Value::Literal(literal) => {
+                        self.relocatable = false;
                         self.hint(literal);
                     }
                     Value::Variable(variable) => {
-                        if let Some(location) = self.variable_location(&variable) {
+                        if let Some(location) = self.variable_location(&variable, 0) {
                             self.hint(location);
                         } else {
                             panic!("WOO!")
                         }
                     }
+                    Value::Offset(base, offset) => {
+                        if let Value::Variable(variable) = *base {
+                            if let Some(location) = self.variable_location(&variable, offset) {
+                                self.hint(location);
+                            } else {
+                                panic!("WOO!")
+                            }
+                        } else {
+                            // SAFETY: Resolving folds offsets from literals into the literal itself.
+                            unreachable!()
+                        }
+                    }
                     Value::Parameter(_) => {
                         // SAFETY: The value has already been resolved,
                         // and the parser ensures that it's the correct type.
@@ -207,7 +659,7 @@ fn build_builtin(&mut self, builtin: &Builtin, values: Option<&ValueList>) -> Re
             }
 
             Builtin::Add(value) => {
-                let value = resolve_value(value, values).unwrap();
+                let value = resolve_value(value, expansion).unwrap();
 
                 if let Value::Literal(literal) = value {
                     self.add(literal);
@@ -218,7 +670,7 @@ fn build_builtin(&mut self, builtin: &Builtin, values: Option<&ValueList>) -> Re
                 }
             }
             Builtin::Subtract(value) => {
-                let value = resolve_value(value, values).unwrap();
+                let value = resolve_value(value, expansion).unwrap();
 
                 if let Value::Literal(literal) = value {
                     self.subtract(literal);
@@ -229,10 +681,10 @@ fn build_builtin(&mut self, builtin: &Builtin, values: Option<&ValueList>) -> Re
                 }
             }
             Builtin::Left(value) => {
-                let value = resolve_value(value, values).unwrap();
+                let value = resolve_value(value, expansion).unwrap();
 
                 if let Value::Literal(literal) = value {
-                    if let Err(_) = self.left(literal) {
+                    if self.left(literal).is_err() {
                         panic!("WOO!")
                     }
                 } else {
@@ -242,7 +694,7 @@ fn build_builtin(&mut self, builtin: &Builtin, values: Option<&ValueList>) -> Re
                 }
             }
             Builtin::Right(value) => {
-                let value = resolve_value(value, values).unwrap();
+                let value = resolve_value(value, expansion).unwrap();
 
                 if let Value::Literal(literal) = value {
                     self.right(literal);
@@ -262,100 +714,602 @@ fn build_builtin(&mut self, builtin: &Builtin, values: Option<&ValueList>) -> Re
                 self.if_zero();
             }
             Builtin::IfNotZero => {
-                self.if_not_zero();
+                self.if_not_zero()?;
             }
         }
 
         Ok(())
     }
 
-    fn allocate(&mut self, name: &str) -> Result<(), ()> {
+    /// Emits hand-written Brainfuck, trusting its declared displacement and effect
+    /// to keep the head and known zeros accurate.
+    fn build_raw(&mut self, raw: &Raw) -> Result<(), ()> {
+        self.instruction_offset += 1;
+        self.relocatable = false;
+
+        let head = match self.head.checked_add_signed(raw.displacement) {
+            Some(head) => head,
+            None => panic!("WOO!"),
+        };
+
+        if self.dead_loops > 0 {
+            return Ok(());
+        }
+
+        self.settle();
+        self.attribute(raw.brainfuck.len());
+        self.nodes_mut().extend(ir::lift(&raw.brainfuck));
+        self.head = head;
+        self.reach(head);
+        self.emitted_head = head;
+
+        match raw.effect {
+            RawEffect::Keep => {
+                let changed: Vec<usize> = self.knowledge.nonzero_cells().collect();
+
+                for location in changed {
+                    self.touch(location);
+                }
+
+                self.knowledge.keep_zeros();
+            }
+            RawEffect::Taint => {
+                for frame in &mut self.loop_frames {
+                    frame.touched_all = true;
+                }
+
+                self.knowledge.forget_all();
+            }
+        }
+
+        // Whatever the raw code did, it can only leave a loop on a zero cell.
+        if let Some(Brainfuck::IfNotZero) = raw.brainfuck.last() {
+            self.learn(self.head, 0);
+        }
+
+        Ok(())
+    }
+
+    fn allocate(&mut self, name: &str, expansion: Option<&Expansion>) -> Result<(), ()> {
         if self.variable_exists(name) {
             panic!("WOO!")
         } else {
-            let location = self.next_allocation;
+            let allocation = Allocation {
+                location: self.next_allocation,
+                size: 0,
+                lifetime: None,
+                placement: self.placements.len(),
+            };
+
+            self.placements.push(Placement {
+                name: name.to_owned(),
+                scope: describe_scope(expansion),
+                lifetime: None,
+            });
 
             // SAFETY: There is always an allocation stack; the top-level stack always exists.
             unsafe {
                 self.allocation_stack
                     .last_mut()
                     .unwrap_unchecked()
-                    .insert(name.to_owned(), location);
+                    .insert(name.to_owned(), allocation);
             }
 
+            self.unreserved.push(name.to_owned());
+
             Ok(())
         }
     }
 
-    #[inline]
+    /// Gives the reserved cells to every variable allocated since the last reservation,
+    /// reusing freed cells when they fit and advancing the allocator otherwise.
     fn reserve(&mut self, amount: usize) {
-        self.next_allocation += amount;
+        if self.unreserved.is_empty() || amount == 0 {
+            self.next_allocation += amount;
+
+            if amount > 0 {
+                self.reach(self.next_allocation - 1);
+            }
+
+            return;
+        }
+
+        let lifetime = self.lifetimes.len();
+
+        // A layout has already decided where every lifetime goes, so the allocator stays out of it
+        // and its free list never mixes the two kinds of address.
+        let (location, fresh) = match &self.layout {
+            Some(layout) => (layout.locations[lifetime], false),
+            None => match self.take_free(amount) {
+                Some(location) => (location, false),
+                None => {
+                    let location = self.next_allocation;
+                    self.next_allocation += amount;
+                    (location, true)
+                }
+            },
+        };
+
+        self.reach(location + amount - 1);
+
+        // Cells that have never been used before start out zero, even where nothing is known
+        // about them anymore, like on a path through a conditional.
+        let zeroed = fresh
+            || (location..location + amount)
+                .all(|location| self.location_is_definitely_zero(location));
+
+        // Cells placed by a layout may have belonged to an earlier variable,
+        // so a variable that relied on starting out zero has to be cleared.
+        if let Some(Clear::OnReserve) = self
+            .layout
+            .as_ref()
+            .and_then(|layout| layout.clears[lifetime])
+        {
+            self.clear(location, amount);
+        }
+
+        self.lifetimes.push(Lifetime {
+            start: self.instruction_offset,
+            end: self.instruction_offset,
+            location,
+            size: amount,
+            zeroed,
+        });
+
+        for name in self.unreserved.drain(..) {
+            for stack in self.allocation_stack.iter_mut().rev() {
+                if let Some(allocation) = stack.get_mut(&name) {
+                    allocation.location = location;
+                    allocation.size = amount;
+                    allocation.lifetime = Some(lifetime);
+                    self.placements[allocation.placement].lifetime = Some(lifetime);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Zeroes every cell in the range that isn't already known to be zero.
+    fn clear(&mut self, location: usize, amount: usize) {
+        if self.dead_loops > 0 {
+            self.deferred_clears.push((location, amount));
+            return;
+        }
+
+        for location in location..location + amount {
+            if self.location_is_definitely_zero(location) {
+                self.rely(location);
+            } else {
+                self.move_to(location);
+                self.set(0);
+            }
+        }
+    }
+
+    fn free(&mut self, name: &str) -> Result<(), ()> {
+        for stack in self.allocation_stack.iter_mut().rev() {
+            if let Some(allocation) = stack.remove(name) {
+                self.give_back(allocation);
+                return Ok(());
+            }
+        }
+
+        // Like any other reference, a macro may free a file-level variable by name.
+        match name.split_once(HYGIENE_SEPARATOR) {
+            Some((global, _)) if self.allocation_stack[0].contains_key(global) => self.free(global),
+            _ => Err(()),
+        }
+    }
+
+    /// Returns cells to the free list, merging them with any neighbouring free cells.
+    fn give_back(&mut self, allocation: Allocation) {
+        // Under a layout, cells are never reused through the free list.
+        if allocation.size == 0 || self.layout.is_some() {
+            return;
+        }
+
+        let index = self
+            .free_list
+            .partition_point(|free| free.location < allocation.location);
+
+        self.free_list.insert(index, allocation);
+
+        if index + 1 < self.free_list.len() {
+            let next = self.free_list[index + 1];
+
+            if allocation.location + allocation.size == next.location {
+                self.free_list[index].size += next.size;
+                self.free_list.remove(index + 1);
+            }
+        }
+
+        if index > 0 {
+            let previous = self.free_list[index - 1];
+
+            if previous.location + previous.size == allocation.location {
+                self.free_list[index - 1].size += self.free_list[index].size;
+                self.free_list.remove(index);
+            }
+        }
+    }
+
+    /// Takes `amount` freed cells, preferring cells that are already known to be zero
+    /// so that reusing them doesn't require clearing, and then the tightest fit.
+    fn take_free(&mut self, amount: usize) -> Option<usize> {
+        let (index, _) = self
+            .free_list
+            .iter()
+            .enumerate()
+            .filter(|(_, free)| free.size >= amount)
+            .min_by_key(|(_, free)| {
+                let zeroed = (free.location..free.location + amount)
+                    .all(|location| self.location_is_definitely_zero(location));
+
+                (!zeroed, free.size)
+            })?;
+
+        let free = &mut self.free_list[index];
+        let location = free.location;
+
+        if free.size == amount {
+            self.free_list.remove(index);
+        } else {
+            free.location += amount;
+            free.size -= amount;
+        }
+
+        Some(location)
     }
 
     fn set(&mut self, value: usize) {
-        if !self.cell_is_definitely_zero() {
-            self.brainfuck.extend_from_slice(&[
-                Brainfuck::IfZero,
-                Brainfuck::Decrement,
-                Brainfuck::IfNotZero,
-            ]);
+        if self.dead_loops > 0 {
+            return;
         }
 
-        if value == 0 {
-            self.known_zeros.insert(self.head);
+        let value = self.reduce(value);
+        let current = self.known(self.head).map(|fact| fact.value);
+
+        // Stepping from a known value can beat clearing and counting from zero.
+        let (from_zero, cost) = self.cheapest_constant(0, value);
+        let cleared = cost + if current == Some(0) { 0 } else { 3 };
+
+        match current.map(|current| self.cheapest_constant(current, value)) {
+            Some((constant, cost)) if cost <= cleared => {
+                self.rely(self.head);
+                self.build_constant(constant);
+            }
+            _ => {
+                self.settle();
+                self.emit(Node::Clear);
+                self.build_constant(from_zero);
+            }
+        }
+
+        self.touch(self.head);
+        self.learn(self.head, value);
+    }
+
+    /// Finds the shortest way of turning one value of the current cell into another, and its length.
+    fn cheapest_constant(&self, from: usize, to: usize) -> (Constant, usize) {
+        let (up, down) = self.steps_to(from, to);
+        let mut cheapest = (Constant::Steps { up, down }, up.min(down));
+
+        if !self.options.generate_constants {
+            return cheapest;
+        }
+
+        let scratch = (1..=SCRATCH_DISTANCE).find_map(|distance| {
+            [Some(self.head + distance), self.head.checked_sub(distance)]
+                .into_iter()
+                .flatten()
+                .find(|&location| {
+                    location < self.options.tape_length
+                        && self.location_is_definitely_zero(location)
+                })
+                .map(|location| (location, distance))
+        });
+
+        let (scratch, distance) = match scratch {
+            Some(scratch) => scratch,
+            None => return cheapest,
+        };
+
+        for (steps, negative) in [(up, false), (down, true)] {
+            if steps == usize::MAX {
+                continue;
+            }
+
+            // The best factors are close to the square root, so there's no need to look much further.
+            let limit = steps.isqrt() * 2 + 1;
+
+            for outer in 2..=limit.min(steps) {
+                let inner = steps / outer;
+                let remainder = steps - outer * inner;
+
+                // Getting there from just above the constant can be shorter than from just below it.
+                let candidates = [
+                    (inner, remainder, false),
+                    (inner + 1, outer * (inner + 1) - steps, true),
+                ];
+
+                for (inner, remainder, overshot) in candidates {
+                    if inner == 0 || (overshot && !self.can_reach(from, outer * inner, negative)) {
+                        continue;
+                    }
+
+                    // Moving to the scratch cell and back, twice, plus both loop brackets and the decrement.
+                    let cost = distance * 4 + outer + inner + 3 + remainder;
+
+                    if cost < cheapest.1 {
+                        let constant = Constant::Product {
+                            scratch,
+                            outer,
+                            inner,
+                            negative,
+                            remainder,
+                            overshot,
+                        };
+
+                        cheapest = (constant, cost);
+                    }
+                }
+            }
+        }
+
+        cheapest
+    }
+
+    /// Whether a cell that doesn't wrap can be moved by `amount` without leaving its range.
+    fn can_reach(&self, from: usize, amount: usize, negative: bool) -> bool {
+        match self.options.cell_width.modulus() {
+            Some(_) if self.options.wrapping => true,
+            _ if negative => amount <= from,
+            Some(modulus) => from.checked_add(amount).is_some_and(|to| to < modulus),
+            None => from.checked_add(amount).is_some(),
+        }
+    }
+
+    fn build_constant(&mut self, constant: Constant) {
+        match constant {
+            Constant::Steps { up, down } => self.step(up, down),
+            Constant::Product {
+                scratch,
+                outer,
+                inner,
+                negative,
+                remainder,
+                overshot,
+            } => {
+                let distance = scratch as isize - self.head as isize;
+                let sign = if negative { -1 } else { 1 };
+                let rest = if overshot { -sign } else { sign };
+
+                // The scratch cell ends up back at zero, so nothing new is learned about it.
+                self.rely(scratch);
+                self.reach(scratch);
+                self.settle();
+
+                self.emit(Node::Move(distance));
+                self.emit(Node::Add {
+                    offset: 0,
+                    amount: outer as isize,
+                });
+                self.emit(Node::MultiplyAdd {
+                    offset: -distance,
+                    factor: sign * inner as isize,
+                });
+                self.emit(Node::Move(-distance));
+
+                if remainder > 0 {
+                    self.emit(Node::Add {
+                        offset: 0,
+                        amount: rest * remainder as isize,
+                    });
+                }
+            }
+        }
+    }
+
+    /// How many increments or decrements it takes to turn one value into another.
+    /// Either may be `usize::MAX` if the cell can't get there that way.
+    fn steps_to(&self, from: usize, to: usize) -> (usize, usize) {
+        match self.options.cell_width.modulus() {
+            Some(modulus) if self.options.wrapping => (
+                (to + modulus - from) % modulus,
+                (from + modulus - to) % modulus,
+            ),
+            _ if to >= from => (to - from, usize::MAX),
+            _ => (usize::MAX, from - to),
+        }
+    }
+
+    /// Emits whichever of the two ways of changing the current cell is shorter.
+    fn step(&mut self, up: usize, down: usize) {
+        if up.min(down) > 0 {
+            self.settle();
+        }
+
+        let amount = if up <= down {
+            up as isize
         } else {
-            self.add(value);
+            -(down as isize)
+        };
+
+        if amount != 0 {
+            self.emit(Node::Add { offset: 0, amount });
+        }
+    }
+
+    /// Brings a literal into the range of a cell, wrapping it around when cells wrap.
+    fn reduce(&self, value: usize) -> usize {
+        match self.options.cell_width.modulus() {
+            Some(modulus) if self.options.wrapping => value % modulus,
+            Some(modulus) if value >= modulus => panic!("WOO: {} does not fit in a cell", value),
+            _ => value,
         }
     }
 
     fn move_to(&mut self, location: usize) {
-        if location > self.head {
-            self.brainfuck
-                .extend(iter::repeat(Brainfuck::Right).take(location - self.head));
-        } else if location < self.head {
-            self.brainfuck
-                .extend(iter::repeat(Brainfuck::Left).take(self.head - location));
+        if self.dead_loops == 0 && location != self.head {
+            self.head = location;
+            self.reach(location);
+
+            // The move is only emitted once something happens on the new cell,
+            // which may well be in another macro than the one that moved there.
+            self.move_cost = location.abs_diff(self.emitted_head);
+            self.move_owner.clone_from(&self.expanding);
         }
+    }
 
-        self.head = location;
+    /// Keeps track of how far along the tape the program gets,
+    /// which only the final layout is checked against.
+    fn reach(&mut self, location: usize) {
+        self.peak = self.peak.max(location + 1);
     }
 
-    fn mark(&mut self) {
-        self.marker = self.next_allocation;
+    /// Emits the moves that bring the head to the current cell, now that something happens there.
+    fn settle(&mut self) {
+        if self.head != self.emitted_head {
+            let amount = self.head as isize - self.emitted_head as isize;
+
+            charge(
+                &mut self.sizes,
+                &mut self.top_level_size,
+                &self.move_owner,
+                self.move_cost,
+            );
+            self.nodes_mut().push(Node::Move(amount));
+        }
+
+        self.emitted_head = self.head;
+    }
+
+    #[inline]
+    fn emit(&mut self, node: Node) {
+        // A loop's brackets were already counted when it was opened and closed.
+        if !matches!(node, Node::Loop(_)) {
+            self.attribute(ir::size(&node));
+        }
+
+        self.nodes_mut().push(node);
+    }
+
+    /// Counts emitted instructions towards every macro that is expanding,
+    /// and towards the innermost one's own size.
+    fn attribute(&mut self, size: usize) {
+        charge(
+            &mut self.sizes,
+            &mut self.top_level_size,
+            &self.expanding,
+            size,
+        );
+    }
+
+    /// The nodes of the innermost open loop, or of the program itself.
+    fn nodes_mut(&mut self) -> &mut Vec<Node> {
+        self.nodes.last_mut().unwrap()
+    }
+
+    /// The finished program. Every loop has been closed by the time it's asked for.
+    fn program(mut self) -> Vec<Node> {
+        self.nodes.swap_remove(0)
+    }
+
+    fn mark(&mut self, marker: Marker) {
+        self.markers.push((marker, self.next_allocation));
 
         let new_stack = self.pool_stack.pop().unwrap_or_default();
         self.allocation_stack.push(new_stack);
+
+        debug_assert_eq!(self.allocation_stack.len(), self.markers.len() + 1);
     }
 
-    fn restore(&mut self) {
-        self.next_allocation = self.marker;
+    /// Closes the innermost marker, which has to have been opened the same way.
+    fn restore(&mut self, marker: Marker) -> Result<(), ()> {
+        let marker = match self.markers.last() {
+            Some(&(opened, location)) if opened == marker => location,
+            // The top-level allocation stack is never restored,
+            // and neither is a block's from inside of it.
+            _ => return Err(()),
+        };
+
+        self.markers.pop();
+
+        self.next_allocation = marker;
+        self.unreserved.clear();
+
+        // SAFETY: Every marker was pushed alongside an allocation stack,
+        // so there is still one above the top-level stack.
+        let mut popped = unsafe { self.allocation_stack.pop().unwrap_unchecked() };
+
+        // Anything past the marker is reclaimed by rewinding,
+        // but variables that reused freed cells below it have to be given back.
+        self.free_list.retain_mut(|free| {
+            free.size = free.size.min(marker.saturating_sub(free.location));
+            free.size > 0
+        });
+
+        for (_, allocation) in popped.drain() {
+            if allocation.location < marker {
+                self.give_back(allocation);
+            }
+        }
 
-        // SAFETY: There is always an allocation stack; the top-level stack always exists.
-        let popped = unsafe { self.allocation_stack.pop().unwrap_unchecked() };
         self.pool_stack.push(popped);
+
+        debug_assert_eq!(self.allocation_stack.len(), self.markers.len() + 1);
+        Ok(())
     }
 
     #[inline]
     fn hint(&mut self, location: usize) {
-        self.known_zeros.insert(location);
+        if self.dead_loops == 0 {
+            self.learn(location, 0);
+        }
     }
 
     fn add(&mut self, amount: usize) {
-        self.taint();
-        self.brainfuck
-            .extend(iter::repeat(Brainfuck::Increment).take(amount % 0xFF));
+        if self.dead_loops > 0 {
+            return;
+        }
+
+        let amount = self.reduce(amount);
+
+        match self.options.cell_width.modulus() {
+            Some(modulus) if self.options.wrapping => {
+                self.step(amount, (modulus - amount) % modulus)
+            }
+            _ => self.step(amount, usize::MAX),
+        }
+
+        self.change_by(amount, false);
     }
 
     fn subtract(&mut self, amount: usize) {
-        self.taint();
-        self.brainfuck
-            .extend(iter::repeat(Brainfuck::Decrement).take(amount % 0xFF));
+        if self.dead_loops > 0 {
+            return;
+        }
+
+        let amount = self.reduce(amount);
+
+        match self.options.cell_width.modulus() {
+            Some(modulus) if self.options.wrapping => {
+                self.step((modulus - amount) % modulus, amount)
+            }
+            _ => self.step(usize::MAX, amount),
+        }
+
+        self.change_by(amount, true);
     }
 
     fn left(&mut self, amount: usize) -> Result<(), ()> {
+        self.relocatable = false;
+
+        if self.dead_loops > 0 {
+            return Ok(());
+        }
+
         if amount <= self.head {
-            self.brainfuck
-                .extend(iter::repeat(Brainfuck::Left).take(amount));
             self.head -= amount;
             Ok(())
         } else {
@@ -364,39 +1318,228 @@ fn left(&mut self, amount: usize) -> Result<(), ()> {
     }
 
     fn right(&mut self, amount: usize) {
-        self.brainfuck
-            .extend(iter::repeat(Brainfuck::Right).take(amount));
+        self.relocatable = false;
+
+        if self.dead_loops > 0 {
+            return;
+        }
+
         self.head += amount;
+        self.reach(self.head);
     }
 
     fn read(&mut self) {
-        self.taint();
-        self.brainfuck.push(Brainfuck::Read);
+        if self.dead_loops > 0 {
+            return;
+        }
+
+        self.touch(self.head);
+        self.knowledge.forget(self.head);
+        self.settle();
+        self.emit(Node::Read);
     }
 
     #[inline]
     fn write(&mut self) {
-        self.brainfuck.push(Brainfuck::Write);
+        if self.dead_loops == 0 {
+            self.settle();
+            self.emit(Node::Write);
+        }
     }
 
-    #[inline]
     fn if_zero(&mut self) {
-        self.brainfuck.push(Brainfuck::IfZero);
+        let ordinal = self.next_loop;
+
+        self.next_loop += 1;
+
+        // Variables reserved somewhere inside of the loop get their cells cleared once, up front,
+        // even if the loop turns out never to run, since they may still be used after it.
+        if let Some(layout) = &self.layout {
+            let start = Some(Clear::BeforeLoop(self.instruction_offset));
+            let clears: Vec<(usize, usize)> = (0..layout.clears.len())
+                .filter(|&lifetime| layout.clears[lifetime] == start)
+                .map(|lifetime| (layout.locations[lifetime], layout.sizes[lifetime]))
+                .collect();
+
+            let head = self.head;
+
+            for (location, size) in clears {
+                self.clear(location, size);
+            }
+
+            self.move_to(head);
+        }
+
+        self.open_loops.push((self.instruction_offset, self.head));
+
+        if self.dead_loops > 0 {
+            self.dead_loops += 1;
+            return;
+        }
+
+        // A loop on a cell that is known to be zero never runs, so none of it is emitted.
+        if self.cell_is_definitely_zero() {
+            self.rely(self.head);
+            self.dead_loops = 1;
+            return;
+        }
+
+        match self.loop_clobbers.get(&ordinal) {
+            Some(clobber) if clobber.all => self.knowledge.forget_all(),
+            Some(clobber) => {
+                for &location in &clobber.cells {
+                    self.knowledge.forget(location);
+                }
+            }
+            None => {}
+        }
+
+        self.loop_frames.push(LoopFrame {
+            ordinal,
+            entry: self.knowledge.clone(),
+            entry_stamp: self.stamp,
+            relied: HashSet::new(),
+            touched: HashSet::new(),
+            touched_all: false,
+        });
+
+        self.settle();
+        self.attribute(1);
+        self.nodes.push(Vec::new());
     }
 
-    #[inline]
-    fn if_not_zero(&mut self) {
-        self.brainfuck.push(Brainfuck::IfNotZero);
+    /// Closes the innermost loop, which has to end on the same cell it started on,
+    /// or else the head would be somewhere different depending on how many times it ran.
+    fn if_not_zero(&mut self) -> Result<(), ()> {
+        match self.open_loops.pop() {
+            Some((start, head)) if head == self.head => {
+                self.loops.push((start, self.instruction_offset));
+            }
+            Some((start, head)) => panic!(
+                "WOO: loop at instruction {} starts on cell {} but ends on cell {}",
+                start, head, self.head
+            ),
+            None => return Err(()),
+        }
+
+        if self.dead_loops > 0 {
+            self.dead_loops -= 1;
+
+            if self.dead_loops == 0 {
+                for (location, amount) in std::mem::take(&mut self.deferred_clears) {
+                    self.clear(location, amount);
+                }
+            }
+
+            return Ok(());
+        }
+
+        self.settle();
+
+        let body = self.nodes.pop().unwrap();
+        self.attribute(1);
+        self.emit(Node::Loop(body));
+
+        let frame = match self.loop_frames.pop() {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+
+        // The body was built assuming it starts from the state before the loop.
+        // If it changes something it relied on, the next iteration starts from something else.
+        let undermined = if frame.touched_all {
+            !frame.relied.is_empty()
+        } else {
+            !frame.relied.is_disjoint(&frame.touched)
+        };
+
+        if undermined {
+            let clobber = self.loop_clobbers.entry(frame.ordinal).or_default();
+
+            clobber.all |= frame.touched_all;
+            clobber.cells.extend(frame.touched);
+
+            self.unstable = true;
+        }
+
+        // The loop may not have run at all, but it certainly left its cell at zero.
+        self.knowledge = self.knowledge.join(&frame.entry);
+        self.learn(self.head, 0);
+
+        Ok(())
     }
 
-    fn taint(&mut self) {
-        self.zero_horizon = self.zero_horizon.max(self.head + 1);
-        self.known_zeros.remove(&self.head);
+    /// Applies a change to the current cell's known value,
+    /// refusing to let a cell that doesn't wrap go out of range.
+    fn change_by(&mut self, amount: usize, negative: bool) {
+        self.touch(self.head);
+
+        if let Some(fact) = self.knowledge.get(self.head) {
+            let value = match (
+                self.options.cell_width.modulus(),
+                self.options.wrapping,
+                negative,
+            ) {
+                (Some(modulus), true, false) => (fact.value + amount) % modulus,
+                (Some(modulus), true, true) => (fact.value + modulus - amount) % modulus,
+                (modulus, false, false) => match fact.value.checked_add(amount) {
+                    Some(value) if modulus.is_none_or(|modulus| value < modulus) => value,
+                    _ => panic!("WOO: cell {} overflows", self.head),
+                },
+                (_, _, true) => match fact.value.checked_sub(amount) {
+                    Some(value) => value,
+                    None => panic!("WOO: cell {} underflows", self.head),
+                },
+                (None, true, false) => fact.value + amount,
+            };
+
+            // The new value is only as old as the one it came from, so a loop that later
+            // relies on it still has to be rebuilt once its body turns out to change the cell.
+            self.knowledge.learn(self.head, value, fact.stamp);
+        }
+    }
+
+    fn learn(&mut self, location: usize, value: usize) {
+        self.stamp += 1;
+        self.knowledge.learn(location, value, self.stamp);
+    }
+
+    /// Records that a cell may change inside of every loop that is currently open.
+    fn touch(&mut self, location: usize) {
+        for frame in &mut self.loop_frames {
+            frame.touched.insert(location);
+        }
+    }
+
+    /// Records that what was emitted depends on what is known about a cell,
+    /// for every open loop that was entered before it was known.
+    fn rely(&mut self, location: usize) {
+        if let Some(fact) = self.known(location) {
+            for frame in &mut self.loop_frames {
+                if fact.stamp <= frame.entry_stamp {
+                    frame.relied.insert(location);
+                }
+            }
+        }
     }
 
     #[inline]
     fn cell_is_definitely_zero(&self) -> bool {
-        self.head >= self.zero_horizon || self.known_zeros.contains(&self.head)
+        self.location_is_definitely_zero(self.head)
+    }
+
+    #[inline]
+    fn location_is_definitely_zero(&self, location: usize) -> bool {
+        self.known(location).is_some_and(|fact| fact.value == 0)
+    }
+
+    /// What the enabled passes are allowed to know about a cell.
+    fn known(&self, location: usize) -> Option<Fact> {
+        match self.knowledge.get(location) {
+            Some(fact) if fact.value == 0 && self.options.track_zeros => Some(fact),
+            Some(fact) if fact.value != 0 && self.options.track_values => Some(fact),
+            _ => None,
+        }
     }
 
     fn variable_exists(&self, name: &str) -> bool {
@@ -409,83 +1552,137 @@ fn variable_exists(&self, name: &str) -> bool {
         false
     }
 
-    fn variable_location(&self, name: &str) -> Option<usize> {
+    /// Finds the cell `offset` cells into a variable,
+    /// making sure that the offset stays within the cells reserved for it.
+    /// Also extends the variable's lifetime to the current instruction.
+    fn variable_location(&mut self, name: &str, offset: usize) -> Option<usize> {
         for stack in self.allocation_stack.iter().rev() {
-            if let Some(location) = stack.get(name) {
-                return Some(*location);
+            if let Some(allocation) = stack.get(name) {
+                if offset > 0 && offset >= allocation.size {
+                    panic!("WOO: {}.{}", name, offset)
+                }
+
+                match allocation.lifetime {
+                    Some(lifetime) => {
+                        self.lifetimes[lifetime].end = self.instruction_offset;
+                        self.accesses.push((lifetime, offset));
+                    }
+                    // Without any reserved cells, the variable is really naming whatever comes next.
+                    None => self.relocatable = false,
+                }
+
+                return Some(allocation.location + offset);
             }
         }
 
-        None
+        // A macro that never allocated a name of its own may still refer to a variable
+        // declared at file level, but never to one its caller allocated anywhere else.
+        match name.split_once(HYGIENE_SEPARATOR) {
+            Some((global, _)) if self.allocation_stack[0].contains_key(global) => {
+                self.variable_location(global, offset)
+            }
+            _ => None,
+        }
     }
 
-    fn save(self, filename: &str) -> Result<(), ()> {
-        match File::create(filename) {
-            Ok(file) => {
-                let mut char_count = 0;
-                let mut writer = BufWriter::new(file);
-
-                for instruction in self.brainfuck {
-                    let result = match instruction {
-                        Brainfuck::Increment => write!(writer, "+"),
-                        Brainfuck::Decrement => write!(writer, "-"),
-                        Brainfuck::Left => write!(writer, "<"),
-                        Brainfuck::Right => write!(writer, ">"),
-                        Brainfuck::Read => write!(writer, ","),
-                        Brainfuck::Write => write!(writer, "."),
-                        Brainfuck::IfZero => write!(writer, "["),
-                        Brainfuck::IfNotZero => write!(writer, "]"),
-                    };
+    fn memory_map(&self) -> MemoryMap {
+        let entries = self
+            .placements
+            .iter()
+            .map(|placement| MemoryEntry {
+                name: placement.name.clone(),
+                scope: placement.scope.clone(),
+                lifetime: placement.lifetime.map(|lifetime| self.lifetimes[lifetime]),
+            })
+            .collect();
+
+        MemoryMap { entries }
+    }
 
-                    match result {
-                        Ok(()) => {
-                            char_count += 1;
+    fn size_report(&self) -> SizeReport {
+        SizeReport {
+            total: ir::lower(&self.nodes[0]).len(),
+            top_level: self.top_level_size,
+            entries: self.sizes.values().cloned().collect(),
+        }
+    }
 
-                            if char_count >= 120 {
-                                char_count = 0;
+    fn pointer_moves(&self) -> usize {
+        ir::lower(&self.nodes[0])
+            .iter()
+            .filter(|instruction| matches!(instruction, Brainfuck::Left | Brainfuck::Right))
+            .count()
+    }
+}
 
-                                if let Err(_) = writeln!(writer) {
-                                    panic!("WOO!")
-                                }
+fn save(brainfuck: &[Brainfuck], filename: &str) -> Result<(), ()> {
+    match File::create(filename) {
+        Ok(file) => {
+            let mut char_count = 0;
+            let mut writer = BufWriter::new(file);
+
+            for instruction in brainfuck {
+                let result = match instruction {
+                    Brainfuck::Increment => write!(writer, "+"),
+                    Brainfuck::Decrement => write!(writer, "-"),
+                    Brainfuck::Left => write!(writer, "<"),
+                    Brainfuck::Right => write!(writer, ">"),
+                    Brainfuck::Read => write!(writer, ","),
+                    Brainfuck::Write => write!(writer, "."),
+                    Brainfuck::IfZero => write!(writer, "["),
+                    Brainfuck::IfNotZero => write!(writer, "]"),
+                };
+
+                match result {
+                    Ok(()) => {
+                        char_count += 1;
+
+                        if char_count >= 120 {
+                            char_count = 0;
+
+                            if writeln!(writer).is_err() {
+                                panic!("WOO!")
                             }
                         }
-                        Err(_) => panic!("WOO!"),
                     }
+                    Err(_) => panic!("WOO!"),
                 }
-
-                Ok(())
             }
-            Err(_) => panic!("WOO!"),
+
+            Ok(())
         }
+        Err(_) => panic!("WOO!"),
     }
 }
 
-fn resolve_value(value: &Value, passed_values: Option<&ValueList>) -> Result<Value, ()> {
+fn resolve_value(value: &Value, expansion: Option<&Expansion>) -> Result<Value, ()> {
     Ok(match value {
         Value::Literal(literal) => Value::Literal(*literal),
         Value::Parameter(parameter) => unsafe {
-            if let Some(passed_values) = passed_values {
-                let resolved_parameter = passed_values.values.get_unchecked(*parameter);
+            if let Some(expansion) = expansion {
+                let resolved_parameter = expansion.values.values.get_unchecked(*parameter);
                 resolved_parameter.clone()
             } else {
                 panic!("WOO!")
             }
         },
-        Value::Variable(variable) => Value::Variable(variable.clone()),
+        Value::Variable(variable) => Value::Variable(resolve_name(variable, expansion)),
+        Value::Offset(base, offset) => match resolve_value(base, expansion)? {
+            Value::Literal(literal) => Value::Literal(literal + offset),
+            Value::Offset(base, inner) => Value::Offset(base, inner + offset),
+            base => Value::Offset(Box::new(base), *offset),
+        },
     })
 }
 
-fn resolve_values(
-    macro_values: &ValueList,
-    passed_values: Option<&ValueList>,
-) -> Result<ValueList, ()> {
+fn resolve_values(macro_values: &ValueList, caller: Option<&Expansion>) -> Result<ValueList, ()> {
     let mut resolved_values = ValueList {
         length: macro_values.length,
         values: Default::default(),
     };
 
     for (index, value) in macro_values.values.iter().enumerate() {
-        match resolve_value(value, passed_values) {
+        match resolve_value(value, caller) {
             Ok(resolved_value) => {
                 // TODO: SAFETY
                 unsafe {
@@ -498,3 +1695,65 @@ fn resolve_values(
 
     Ok(resolved_values)
 }
+
+/// Names the chain of expansions that leads to the given one, outermost first.
+fn describe_scope(expansion: Option<&Expansion>) -> String {
+    let mut chain = Vec::new();
+    let mut current = expansion;
+
+    while let Some(expansion) = current {
+        chain.push(format!("{}#{}", expansion.name, expansion.id));
+        current = expansion.parent;
+    }
+
+    if chain.is_empty() {
+        "top level".to_owned()
+    } else {
+        chain.reverse();
+        chain.join(" > ")
+    }
+}
+
+/// Renames a variable written in a macro body so that it is private to the current expansion.
+/// Exported names are resolved as though the caller had written them.
+fn resolve_name(name: &str, expansion: Option<&Expansion>) -> String {
+    match expansion {
+        Some(expansion) if expansion.exports.iter().any(|export| export == name) => {
+            resolve_name(name, expansion.parent)
+        }
+        Some(expansion) => format!("{}{}{}", name, HYGIENE_SEPARATOR, expansion.id),
+        None => name.to_owned(),
+    }
+}
+
+/// Counts instructions towards every macro in `owner`, and towards the innermost one's own size,
+/// or towards the top level if it's empty.
+fn charge(
+    sizes: &mut HashMap<String, SizeEntry>,
+    top_level_size: &mut usize,
+    owner: &[String],
+    size: usize,
+) {
+    let innermost = match owner.last() {
+        Some(innermost) => innermost,
+        None => {
+            *top_level_size += size;
+            return;
+        }
+    };
+
+    if let Some(entry) = sizes.get_mut(innermost) {
+        entry.own += size;
+    }
+
+    // A recursive macro is only responsible for each instruction once.
+    let mut counted = HashSet::new();
+
+    for name in owner {
+        if counted.insert(name) {
+            if let Some(entry) = sizes.get_mut(name) {
+                entry.total += size;
+            }
+        }
+    }
+}
@@ -0,0 +1,467 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::io::{Read, Write};
+
+use crate::interpreter::{Eof, Fault, Machine};
+use crate::language::Brainfuck;
+
+/// A single step of a precompiled program.
+#[derive(Copy, Clone, Debug)]
+enum Op {
+    /// A run of the same `+` or `-`, as a signed amount.
+    Add(i64),
+    /// A run of the same `<` or `>`, as a signed distance.
+    Move(isize),
+    Read,
+    Write,
+    /// Jumps past the matching `JumpIfNonZero` when the current cell is zero.
+    JumpIfZero(usize),
+    /// Jumps back past the matching `JumpIfZero` when the current cell isn't zero.
+    JumpIfNonZero(usize),
+    Clear,
+    /// Adds `factor` times the current cell to the cell `offset` cells away.
+    /// Always followed by the `Clear` that ends the loop it came from.
+    MultiplyAdd {
+        offset: isize,
+        factor: i64,
+    },
+}
+
+/// A program ready to be run, remembering where each op came from for faults.
+pub struct Program {
+    ops: Vec<Op>,
+    positions: Vec<usize>,
+    /// The instructions the program was compiled from,
+    /// for finding exactly where a multiplication loop faults.
+    source: Vec<Brainfuck>,
+}
+
+impl Program {
+    /// Merges runs, resolves every jump ahead of time,
+    /// and turns simple loops into clears and multiplications.
+    pub fn compile(brainfuck: &[Brainfuck], machine: &Machine) -> Result<Self, Fault> {
+        let mut program = Program {
+            ops: Vec::with_capacity(brainfuck.len()),
+            positions: Vec::with_capacity(brainfuck.len()),
+            source: brainfuck.to_vec(),
+        };
+
+        let mut open = Vec::new();
+        let mut index = 0;
+
+        while index < brainfuck.len() {
+            let instruction = brainfuck[index];
+
+            if instruction == Brainfuck::IfZero {
+                if let Some((ops, length)) = simple_loop(&brainfuck[index..], machine) {
+                    for op in ops {
+                        program.push(op, index);
+                    }
+
+                    index += length;
+                    continue;
+                }
+            }
+
+            let run = brainfuck[index..]
+                .iter()
+                .take_while(|&&next| next == instruction)
+                .count();
+
+            match instruction {
+                Brainfuck::Increment => program.push(Op::Add(run as i64), index),
+                Brainfuck::Decrement => program.push(Op::Add(-(run as i64)), index),
+                Brainfuck::Right => program.push(Op::Move(run as isize), index),
+                Brainfuck::Left => program.push(Op::Move(-(run as isize)), index),
+                _ => {
+                    // Everything else happens once per instruction.
+                    for offset in 0..run {
+                        let position = index + offset;
+
+                        match instruction {
+                            Brainfuck::Read => program.push(Op::Read, position),
+                            Brainfuck::Write => program.push(Op::Write, position),
+                            Brainfuck::IfZero => {
+                                open.push(program.ops.len());
+                                program.push(Op::JumpIfZero(0), position);
+                            }
+                            _ => {
+                                let start = open.pop().ok_or(Fault::UnmatchedBracket(position))?;
+                                let end = program.ops.len();
+
+                                program.ops[start] = Op::JumpIfZero(end);
+                                program.push(Op::JumpIfNonZero(start), position);
+                            }
+                        }
+                    }
+                }
+            }
+
+            index += run;
+        }
+
+        match open.pop() {
+            Some(start) => Err(Fault::UnmatchedBracket(program.positions[start])),
+            None => Ok(program),
+        }
+    }
+
+    fn push(&mut self, op: Op, position: usize) {
+        self.ops.push(op);
+        self.positions.push(position);
+    }
+
+    /// Runs the program, which behaves exactly as the plain interpreter would.
+    pub fn run(
+        &self,
+        machine: &Machine,
+        mut input: impl Read,
+        mut output: impl Write,
+    ) -> Result<(), Fault> {
+        let max = machine.max_value();
+        let mut tape = vec![0u64; machine.tape_length];
+        let mut head = 0;
+        let mut index = 0;
+
+        while index < self.ops.len() {
+            let position = self.positions[index];
+
+            match self.ops[index] {
+                Op::Add(amount) => {
+                    tape[head] = add(tape[head], amount, machine, max)
+                        .ok_or_else(|| out_of_range(tape[head], amount, max, position))?;
+                }
+                Op::Move(distance) => {
+                    head = step(head, distance, tape.len(), position)?;
+                }
+                Op::Read => {
+                    output.flush().map_err(Fault::Io)?;
+
+                    let mut byte = [0];
+
+                    match input.read(&mut byte).map_err(Fault::Io)? {
+                        0 => match machine.eof {
+                            Eof::Zero => tape[head] = 0,
+                            Eof::Unchanged => {}
+                            Eof::MinusOne => tape[head] = max,
+                        },
+                        _ => tape[head] = byte[0] as u64,
+                    }
+                }
+                Op::Write => {
+                    output.write_all(&[tape[head] as u8]).map_err(Fault::Io)?;
+                }
+                Op::JumpIfZero(end) if tape[head] == 0 => index = end,
+                Op::JumpIfNonZero(start) if tape[head] != 0 => index = start,
+                Op::JumpIfZero(_) | Op::JumpIfNonZero(_) => {}
+                Op::Clear => tape[head] = 0,
+                Op::MultiplyAdd { .. } => {
+                    // A loop's multiplications all run here at once, and only once every one of
+                    // them is known to succeed. Otherwise the loop runs the slow way to find
+                    // exactly which instruction faults, and when.
+                    let end = index
+                        + self.ops[index..]
+                            .iter()
+                            .take_while(|op| matches!(op, Op::MultiplyAdd { .. }))
+                            .count();
+
+                    let value = tape[head];
+
+                    if value != 0 {
+                        for pass in [false, true] {
+                            for &op in &self.ops[index..end] {
+                                let Op::MultiplyAdd { offset, factor } = op else {
+                                    unreachable!()
+                                };
+
+                                let sum = head
+                                    .checked_add_signed(offset)
+                                    .filter(|&target| target < tape.len())
+                                    .and_then(|target| {
+                                        let amount = multiply(value, factor, machine)?;
+                                        Some((target, add(tape[target], amount, machine, max)?))
+                                    });
+
+                                match sum {
+                                    Some((target, sum)) if pass => tape[target] = sum,
+                                    Some(_) => {}
+                                    None => {
+                                        return Err(self
+                                            .loop_fault(position, &mut tape, head, machine, max));
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    index = end - 1;
+                }
+            }
+
+            index += 1;
+        }
+
+        output.flush().map_err(Fault::Io)
+    }
+
+    /// Runs the simple loop starting at `start` one instruction at a time,
+    /// returning the fault it has already been found to run into.
+    fn loop_fault(
+        &self,
+        start: usize,
+        tape: &mut [u64],
+        mut head: usize,
+        machine: &Machine,
+        max: u64,
+    ) -> Fault {
+        let mut position = start + 1;
+
+        loop {
+            let result = match self.source[position] {
+                Brainfuck::Increment => add(tape[head], 1, machine, max)
+                    .map(|value| tape[head] = value)
+                    .ok_or(Fault::OutOfRange(position)),
+                Brainfuck::Decrement => add(tape[head], -1, machine, max)
+                    .map(|value| tape[head] = value)
+                    .ok_or(Fault::OutOfRange(position)),
+                Brainfuck::Right => step(head, 1, tape.len(), position).map(|next| head = next),
+                Brainfuck::Left => step(head, -1, tape.len(), position).map(|next| head = next),
+                // The loop faults before its own cell counts down to zero, so it goes around again.
+                _ => {
+                    position = start;
+                    Ok(())
+                }
+            };
+
+            if let Err(fault) = result {
+                return fault;
+            }
+
+            position += 1;
+        }
+    }
+}
+
+/// Recognizes `[-]` and loops that only add multiples of the current cell to others,
+/// returning the ops that replace the loop and how many instructions it took up.
+fn simple_loop(brainfuck: &[Brainfuck], machine: &Machine) -> Option<(Vec<Op>, usize)> {
+    let mut offset: isize = 0;
+    let mut lowest: isize = 0;
+    let mut highest: isize = 0;
+    let mut changes: Vec<(isize, i64)> = Vec::new();
+
+    for (length, &instruction) in brainfuck.iter().enumerate().skip(1) {
+        let amount = match instruction {
+            Brainfuck::Increment => 1,
+            Brainfuck::Decrement => -1,
+            Brainfuck::Left => {
+                offset -= 1;
+                lowest = lowest.min(offset);
+                continue;
+            }
+            Brainfuck::Right => {
+                offset += 1;
+                highest = highest.max(offset);
+                continue;
+            }
+            Brainfuck::IfNotZero => {
+                // The loop must count its own cell down by one each time around,
+                // and end up back where it started.
+                let own: i64 = changes
+                    .iter()
+                    .filter(|(change, _)| *change == 0)
+                    .map(|(_, amount)| amount)
+                    .sum();
+
+                if offset != 0 || own != -1 {
+                    return None;
+                }
+
+                // Cells that don't wrap could fault partway through the loop, which a single
+                // multiplication can only match if each cell only ever goes one way.
+                if !machine.wrapping
+                    && changes.iter().any(|&(change, amount)| {
+                        changes
+                            .iter()
+                            .any(|&(other, other_amount)| other == change && other_amount != amount)
+                    })
+                {
+                    return None;
+                }
+
+                // Likewise, the loop mustn't wander past the cells it changes,
+                // where it could run off the tape without anything to show for it.
+                let reached = changes.iter().map(|&(change, _)| change);
+
+                if reached.clone().min().is_none_or(|low| low > lowest)
+                    || reached.max().is_none_or(|high| high < highest)
+                {
+                    return None;
+                }
+
+                let mut factors: Vec<(isize, i64)> = Vec::new();
+
+                for (change, amount) in changes {
+                    if change == 0 {
+                        continue;
+                    }
+
+                    match factors.iter_mut().find(|(offset, _)| *offset == change) {
+                        Some((_, factor)) => *factor += amount,
+                        None => factors.push((change, amount)),
+                    }
+                }
+
+                let mut ops: Vec<Op> = factors
+                    .into_iter()
+                    .filter(|&(_, factor)| factor != 0)
+                    .map(|(offset, factor)| Op::MultiplyAdd { offset, factor })
+                    .collect();
+
+                ops.push(Op::Clear);
+                return Some((ops, length + 1));
+            }
+            _ => return None,
+        };
+
+        changes.push((offset, amount));
+    }
+
+    None
+}
+
+fn add(value: u64, amount: i64, machine: &Machine, max: u64) -> Option<u64> {
+    if machine.wrapping {
+        let modulus = max as u128 + 1;
+        let amount = (amount as i128).rem_euclid(modulus as i128) as u128;
+        Some(((value as u128 + amount) % modulus) as u64)
+    } else {
+        value
+            .checked_add_signed(amount)
+            .filter(|&value| value <= max)
+    }
+}
+
+fn multiply(value: u64, factor: i64, machine: &Machine) -> Option<i64> {
+    if machine.wrapping {
+        // Only the result modulo the cell size matters, and that survives the truncation.
+        Some((value as i64).wrapping_mul(factor))
+    } else {
+        i64::try_from(value).ok()?.checked_mul(factor)
+    }
+}
+
+/// The fault of a run of `+` or `-` that leaves the range, at the exact instruction that does.
+fn out_of_range(value: u64, amount: i64, max: u64, position: usize) -> Fault {
+    let steps = if amount > 0 { max - value } else { value };
+    Fault::OutOfRange(position + steps as usize)
+}
+
+/// Moves the head, faulting at the exact instruction that leaves the tape.
+fn step(head: usize, distance: isize, length: usize, position: usize) -> Result<usize, Fault> {
+    match head.checked_add_signed(distance) {
+        Some(head) if head < length => Ok(head),
+        Some(_) => Err(Fault::PastTape(position + (length - head) - 1)),
+        None => Err(Fault::BelowTape(position + head)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{self, parse};
+    use crate::language::CellWidth;
+
+    fn machine(wrapping: bool) -> Machine {
+        Machine {
+            cell_width: CellWidth::Bits8,
+            wrapping,
+            tape_length: 8,
+            eof: Eof::Zero,
+        }
+    }
+
+    /// Both interpreters have to print the same thing, and fail at the same instruction.
+    fn assert_agree(source: &str, machine: &Machine) {
+        let brainfuck = parse(source);
+
+        let mut naive = Vec::new();
+        let naive_result = interpreter::run(&brainfuck, machine, &b""[..], &mut naive);
+
+        let mut fast = Vec::new();
+        let fast_result = Program::compile(&brainfuck, machine)
+            .and_then(|program| program.run(machine, &b""[..], &mut fast));
+
+        assert_eq!(
+            fast_result.map_err(|fault| fault.to_string()),
+            naive_result.map_err(|fault| fault.to_string()),
+            "{}",
+            source
+        );
+        assert_eq!(fast, naive, "{}", source);
+    }
+
+    #[test]
+    fn clears() {
+        for wrapping in [true, false] {
+            assert_agree("+++++[-].", &machine(wrapping));
+            assert_agree("+++>++[-]<[-].>.", &machine(wrapping));
+            assert_agree("[-].", &machine(wrapping));
+        }
+    }
+
+    #[test]
+    fn multiply_loops() {
+        for wrapping in [true, false] {
+            assert_agree("+++[>++<-]>.", &machine(wrapping));
+            assert_agree("++++[>+++>++<<-]>.>.", &machine(wrapping));
+            assert_agree("+++[>+>-<+<-]>.", &machine(wrapping));
+            assert_agree(">+++[<++>-]<.", &machine(wrapping));
+        }
+    }
+
+    #[test]
+    fn wrapping() {
+        let machine = machine(true);
+
+        assert_agree("-.", &machine);
+        assert_agree(&"+".repeat(300), &machine);
+        assert_agree("+++[>-<-]>.", &machine);
+        assert_agree("-[>++<-]>.", &machine);
+    }
+
+    #[test]
+    fn faults_partway_through_loops() {
+        let machine = machine(false);
+
+        // Going below zero, in a run and in a multiplication.
+        assert_agree("-", &machine);
+        assert_agree("++--->", &machine);
+        assert_agree("+++[>-<-]", &machine);
+        assert_agree(">++<+++[>>+<-<-]", &machine);
+
+        // Going past the largest value on a later time around.
+        assert_agree(&format!("{}[>+++<-]", "+".repeat(100)), &machine);
+        assert_agree(
+            &format!(">{}<+++[>{}<-]", "+".repeat(200), "+".repeat(20)),
+            &machine,
+        );
+
+        // Running off either end of the tape.
+        assert_agree("<", &machine);
+        assert_agree(">>>>>>>>>>", &machine);
+        assert_agree("+[<+>-]", &machine);
+        assert_agree(">>>>>>+[>>+<<-]", &machine);
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        for source in ["]", "+]", "[", "[[]", "+[-]]"] {
+            assert_agree(source, &machine(true));
+        }
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use crate::language::{Brainfuck, CellWidth};
+
+/// What reading does to the current cell once the input has run out.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Eof {
+    Zero,
+    Unchanged,
+    /// The largest value a cell can hold, which is -1 for cells that wrap.
+    MinusOne,
+}
+
+impl TryFrom<&str> for Eof {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(match value {
+            "0" => Self::Zero,
+            "unchanged" => Self::Unchanged,
+            "-1" => Self::MinusOne,
+
+            _ => return Err(()),
+        })
+    }
+}
+
+/// The machine a program runs on.
+#[derive(Copy, Clone, Debug)]
+pub struct Machine {
+    pub cell_width: CellWidth,
+    pub wrapping: bool,
+    pub tape_length: usize,
+    pub eof: Eof,
+}
+
+impl Machine {
+    /// The largest value a cell can hold.
+    pub fn max_value(&self) -> u64 {
+        match self.cell_width.modulus() {
+            Some(modulus) => modulus as u64 - 1,
+            None => u64::MAX,
+        }
+    }
+}
+
+/// Something that stopped a program partway through, by index into its instructions.
+#[derive(Debug)]
+pub enum Fault {
+    UnmatchedBracket(usize),
+    BelowTape(usize),
+    PastTape(usize),
+    /// A cell that doesn't wrap went past either end of its range.
+    OutOfRange(usize),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnmatchedBracket(index) => write!(f, "bracket at {} has no match", index),
+            Self::BelowTape(index) => write!(f, "`<` at {} moved left of the first cell", index),
+            Self::PastTape(index) => write!(f, "`>` at {} moved past the end of the tape", index),
+            Self::OutOfRange(index) => write!(f, "instruction {} took a cell out of range", index),
+            Self::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Reads Brainfuck source, skipping everything that isn't an instruction.
+pub fn parse(source: &str) -> Vec<Brainfuck> {
+    source
+        .chars()
+        .filter_map(|c| match c {
+            '+' => Some(Brainfuck::Increment),
+            '-' => Some(Brainfuck::Decrement),
+            '<' => Some(Brainfuck::Left),
+            '>' => Some(Brainfuck::Right),
+            ',' => Some(Brainfuck::Read),
+            '.' => Some(Brainfuck::Write),
+            '[' => Some(Brainfuck::IfZero),
+            ']' => Some(Brainfuck::IfNotZero),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Runs a program one instruction at a time, finding the other end of a loop
+/// by scanning for it whenever it has to jump.
+///
+/// Every bracket is checked for a match up front, like a compiled program's would be,
+/// so a program that never reaches its stray bracket still fails.
+pub fn run(
+    brainfuck: &[Brainfuck],
+    machine: &Machine,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<(), Fault> {
+    check_brackets(brainfuck)?;
+
+    let max = machine.max_value();
+    let mut tape = vec![0u64; machine.tape_length];
+    let mut head = 0;
+    let mut index = 0;
+
+    while index < brainfuck.len() {
+        match brainfuck[index] {
+            Brainfuck::Increment => {
+                tape[head] = match tape[head] {
+                    value if value < max => value + 1,
+                    _ if machine.wrapping => 0,
+                    _ => return Err(Fault::OutOfRange(index)),
+                };
+            }
+            Brainfuck::Decrement => {
+                tape[head] = match tape[head] {
+                    value if value > 0 => value - 1,
+                    _ if machine.wrapping => max,
+                    _ => return Err(Fault::OutOfRange(index)),
+                };
+            }
+            Brainfuck::Left => {
+                head = head.checked_sub(1).ok_or(Fault::BelowTape(index))?;
+            }
+            Brainfuck::Right => {
+                head += 1;
+
+                if head >= tape.len() {
+                    return Err(Fault::PastTape(index));
+                }
+            }
+            Brainfuck::Read => {
+                // Anyone waiting on the prompt should see it before being asked for input.
+                output.flush().map_err(Fault::Io)?;
+
+                let mut byte = [0];
+
+                match input.read(&mut byte).map_err(Fault::Io)? {
+                    0 => match machine.eof {
+                        Eof::Zero => tape[head] = 0,
+                        Eof::Unchanged => {}
+                        Eof::MinusOne => tape[head] = max,
+                    },
+                    _ => tape[head] = byte[0] as u64,
+                }
+            }
+            Brainfuck::Write => {
+                output.write_all(&[tape[head] as u8]).map_err(Fault::Io)?;
+            }
+            Brainfuck::IfZero if tape[head] == 0 => {
+                index = matching(brainfuck, index).ok_or(Fault::UnmatchedBracket(index))?;
+            }
+            Brainfuck::IfNotZero if tape[head] != 0 => {
+                index = matching(brainfuck, index).ok_or(Fault::UnmatchedBracket(index))?;
+            }
+            Brainfuck::IfZero | Brainfuck::IfNotZero => {}
+        }
+
+        index += 1;
+    }
+
+    output.flush().map_err(Fault::Io)
+}
+
+/// Faults at the first `]` without a match, or else at the innermost `[` left open.
+fn check_brackets(brainfuck: &[Brainfuck]) -> Result<(), Fault> {
+    let mut open = Vec::new();
+
+    for (index, &instruction) in brainfuck.iter().enumerate() {
+        match instruction {
+            Brainfuck::IfZero => open.push(index),
+            Brainfuck::IfNotZero => {
+                open.pop().ok_or(Fault::UnmatchedBracket(index))?;
+            }
+            _ => {}
+        }
+    }
+
+    match open.pop() {
+        Some(index) => Err(Fault::UnmatchedBracket(index)),
+        None => Ok(()),
+    }
+}
+
+/// Finds the bracket that matches the one at `index`.
+fn matching(brainfuck: &[Brainfuck], index: usize) -> Option<usize> {
+    let mut depth = 0usize;
+
+    if brainfuck[index] == Brainfuck::IfZero {
+        for (offset, instruction) in brainfuck[index..].iter().enumerate() {
+            match instruction {
+                Brainfuck::IfZero => depth += 1,
+                Brainfuck::IfNotZero => depth -= 1,
+                _ => continue,
+            }
+
+            if depth == 0 {
+                return Some(index + offset);
+            }
+        }
+    } else {
+        for (offset, instruction) in brainfuck[..=index].iter().rev().enumerate() {
+            match instruction {
+                Brainfuck::IfNotZero => depth += 1,
+                Brainfuck::IfZero => depth -= 1,
+                _ => continue,
+            }
+
+            if depth == 0 {
+                return Some(index - offset);
+            }
+        }
+    }
+
+    None
+}
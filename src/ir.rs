@@ -0,0 +1,148 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::iter;
+
+use crate::language::Brainfuck;
+
+/// A structured instruction, which is what expansion produces and what every later pass works on.
+/// Offsets are relative to the head, and no node other than `Move` leaves the head somewhere else.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Node {
+    /// Adds to (or, when negative, subtracts from) the cell `offset` cells away.
+    Add {
+        offset: isize,
+        amount: isize,
+    },
+    Move(isize),
+    Read,
+    Write,
+    /// Runs its body until the current cell is zero.
+    Loop(Vec<Node>),
+    /// Sets the current cell to zero.
+    Clear,
+    /// Adds `factor` times the current cell to the cell `offset` cells away, leaving the current cell zero.
+    MultiplyAdd {
+        offset: isize,
+        factor: isize,
+    },
+}
+
+/// Turns Brainfuck into nodes, such as hand-written code that has to sit among them.
+/// The brackets must be balanced.
+pub fn lift(brainfuck: &[Brainfuck]) -> Vec<Node> {
+    let mut stack = vec![Vec::new()];
+
+    for &instruction in brainfuck {
+        let nodes = stack.last_mut().unwrap();
+
+        match instruction {
+            Brainfuck::Increment => push_add(nodes, 1),
+            Brainfuck::Decrement => push_add(nodes, -1),
+            Brainfuck::Right => push_move(nodes, 1),
+            Brainfuck::Left => push_move(nodes, -1),
+            Brainfuck::Read => nodes.push(Node::Read),
+            Brainfuck::Write => nodes.push(Node::Write),
+            Brainfuck::IfZero => stack.push(Vec::new()),
+            Brainfuck::IfNotZero => {
+                let body = stack.pop().unwrap();
+                stack.last_mut().unwrap().push(Node::Loop(body));
+            }
+        }
+    }
+
+    stack.pop().unwrap()
+}
+
+fn push_add(nodes: &mut Vec<Node>, amount: isize) {
+    match nodes.last_mut() {
+        Some(Node::Add {
+            offset: 0,
+            amount: previous,
+        }) => *previous += amount,
+        _ => nodes.push(Node::Add { offset: 0, amount }),
+    }
+}
+
+fn push_move(nodes: &mut Vec<Node>, amount: isize) {
+    match nodes.last_mut() {
+        Some(Node::Move(previous)) => *previous += amount,
+        _ => nodes.push(Node::Move(amount)),
+    }
+}
+
+/// How many Brainfuck instructions a node lowers to, not counting the body of a loop.
+pub fn size(node: &Node) -> usize {
+    match node {
+        Node::Add { offset, amount } => offset.unsigned_abs() * 2 + amount.unsigned_abs(),
+        Node::Move(amount) => amount.unsigned_abs(),
+        Node::Read | Node::Write => 1,
+        Node::Loop(_) => 2,
+        Node::Clear => 3,
+        Node::MultiplyAdd { offset, factor } => {
+            offset.unsigned_abs() * 2 + factor.unsigned_abs() + 3
+        }
+    }
+}
+
+/// Turns nodes back into Brainfuck.
+pub fn lower(nodes: &[Node]) -> Vec<Brainfuck> {
+    let mut brainfuck = Vec::new();
+    lower_into(nodes, &mut brainfuck);
+    brainfuck
+}
+
+fn lower_into(nodes: &[Node], brainfuck: &mut Vec<Brainfuck>) {
+    for node in nodes {
+        match node {
+            Node::Add { offset, amount } => {
+                lower_move(*offset, brainfuck);
+                lower_add(*amount, brainfuck);
+                lower_move(-offset, brainfuck);
+            }
+            Node::Move(amount) => lower_move(*amount, brainfuck),
+            Node::Read => brainfuck.push(Brainfuck::Read),
+            Node::Write => brainfuck.push(Brainfuck::Write),
+            Node::Loop(body) => {
+                brainfuck.push(Brainfuck::IfZero);
+                lower_into(body, brainfuck);
+                brainfuck.push(Brainfuck::IfNotZero);
+            }
+            Node::Clear => brainfuck.extend_from_slice(&[
+                Brainfuck::IfZero,
+                Brainfuck::Decrement,
+                Brainfuck::IfNotZero,
+            ]),
+            Node::MultiplyAdd { offset, factor } => {
+                brainfuck.push(Brainfuck::IfZero);
+                lower_move(*offset, brainfuck);
+                lower_add(*factor, brainfuck);
+                lower_move(-offset, brainfuck);
+                brainfuck.extend_from_slice(&[Brainfuck::Decrement, Brainfuck::IfNotZero]);
+            }
+        }
+    }
+}
+
+fn lower_add(amount: isize, brainfuck: &mut Vec<Brainfuck>) {
+    let instruction = if amount < 0 {
+        Brainfuck::Decrement
+    } else {
+        Brainfuck::Increment
+    };
+
+    brainfuck.extend(iter::repeat_n(instruction, amount.unsigned_abs()));
+}
+
+fn lower_move(amount: isize, brainfuck: &mut Vec<Brainfuck>) {
+    let instruction = if amount < 0 {
+        Brainfuck::Left
+    } else {
+        Brainfuck::Right
+    };
+
+    brainfuck.extend(iter::repeat_n(instruction, amount.unsigned_abs()));
+}
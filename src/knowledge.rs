@@ -0,0 +1,111 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::HashMap;
+
+const KNOWN_VALUE_CAPACITY: usize = 32;
+
+/// The value a cell is known to hold, and when the compiler learned it.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Fact {
+    pub value: usize,
+    /// Facts learned later have larger stamps; untouched cells have a stamp of zero.
+    pub stamp: usize,
+}
+
+/// What the compiler knows about every cell at the current point in the program.
+#[derive(Clone, Debug)]
+pub struct Knowledge {
+    values: HashMap<usize, Fact>,
+    /// Every cell at or past the horizon has never been touched, and so is still zero.
+    horizon: usize,
+}
+
+impl Knowledge {
+    pub fn new() -> Self {
+        Self {
+            values: HashMap::with_capacity(KNOWN_VALUE_CAPACITY),
+            horizon: 0,
+        }
+    }
+
+    pub fn get(&self, location: usize) -> Option<Fact> {
+        if location >= self.horizon {
+            Some(Fact { value: 0, stamp: 0 })
+        } else {
+            self.values.get(&location).copied()
+        }
+    }
+
+    pub fn learn(&mut self, location: usize, value: usize, stamp: usize) {
+        self.extend_horizon(location + 1);
+        self.values.insert(location, Fact { value, stamp });
+    }
+
+    pub fn forget(&mut self, location: usize) {
+        self.extend_horizon(location + 1);
+        self.values.remove(&location);
+    }
+
+    pub fn forget_all(&mut self) {
+        self.values.clear();
+        self.horizon = usize::MAX;
+    }
+
+    /// Forgets every cell except those known to be zero.
+    pub fn keep_zeros(&mut self) {
+        self.values.retain(|_, fact| fact.value == 0);
+    }
+
+    /// Every cell known to hold something other than zero.
+    pub fn nonzero_cells(&self) -> impl Iterator<Item = usize> + '_ {
+        self.values
+            .iter()
+            .filter(|(_, fact)| fact.value != 0)
+            .map(|(&location, _)| location)
+    }
+
+    /// What is known no matter which of the two states the program is actually in,
+    /// such as after a loop that may or may not have run.
+    pub fn join(&self, other: &Knowledge) -> Knowledge {
+        let horizon = self.horizon.max(other.horizon);
+        let mut values = HashMap::with_capacity(KNOWN_VALUE_CAPACITY);
+
+        // Below the larger horizon, anything not explicitly known by that state is unknown,
+        // so only explicitly known cells can survive the join.
+        for &location in self.values.keys().chain(other.values.keys()) {
+            if location >= horizon {
+                continue;
+            }
+
+            if let (Some(a), Some(b)) = (self.get(location), other.get(location)) {
+                if a.value == b.value {
+                    let stamp = a.stamp.min(b.stamp);
+                    values.insert(
+                        location,
+                        Fact {
+                            value: a.value,
+                            stamp,
+                        },
+                    );
+                }
+            }
+        }
+
+        Knowledge { values, horizon }
+    }
+
+    /// Moves the horizon outward, remembering that the cells it passes over are still zero.
+    fn extend_horizon(&mut self, horizon: usize) {
+        if horizon > self.horizon {
+            for location in self.horizon..horizon {
+                self.values.insert(location, Fact { value: 0, stamp: 0 });
+            }
+
+            self.horizon = horizon;
+        }
+    }
+}
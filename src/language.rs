@@ -7,6 +7,7 @@
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum BuiltinReference {
     Allocate,
+    Free,
     Reserve,
     Set,
     Move,
@@ -30,6 +31,7 @@ impl TryFrom<&str> for BuiltinReference {
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         Ok(match value {
             "allocate" => Self::Allocate,
+            "free" => Self::Free,
             "reserve" => Self::Reserve,
             "set" => Self::Set,
             "move" => Self::Move,
@@ -56,6 +58,8 @@ pub enum Value {
     Literal(usize),
     Parameter(usize),
     Variable(String),
+    /// A cell some distance into a variable, written `name.offset` or `@0.offset`.
+    Offset(Box<Value>, usize),
 }
 
 impl Default for Value {
@@ -68,6 +72,7 @@ fn default() -> Self {
 #[derive(Debug)]
 pub enum Builtin {
     Allocate(Value),
+    Free(Value),
     Reserve(Value),
     Set(Value),
     Move(Value),
@@ -90,6 +95,10 @@ pub enum Directive {
     Parameter(usize),
     // TODO: Define(String, Value),
     Macro,
+    Export,
+    Namespace,
+    Use,
+    Raw,
     Include,
 }
 
@@ -110,6 +119,10 @@ fn try_from(value: &str) -> Result<Self, Self::Error> {
             "9" => Self::Parameter(9),
 
             "macro" => Self::Macro,
+            "export" => Self::Export,
+            "namespace" => Self::Namespace,
+            "use" => Self::Use,
+            "raw" => Self::Raw,
             "include" => Self::Include,
 
             _ => return Err(()),
@@ -124,6 +137,7 @@ pub enum Token {
     Directive(Directive),
 
     Integer(usize),
+    Offset(usize),
     String(String),
 
     LeftBrace,
@@ -138,16 +152,86 @@ pub struct ValueList {
 
 #[derive(Debug)]
 pub enum Instruction {
-    Macro(String, ValueList),
+    Macro(String, Box<ValueList>),
     Builtin(Builtin),
+    Raw(Raw),
+    /// Instructions between braces; anything allocated inside is freed at the closing brace.
+    Block(Vec<Instruction>),
+}
+
+/// What hand-written Brainfuck may do to cells that the compiler knows are zero.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RawEffect {
+    /// Every cell that was zero beforehand is still zero afterwards.
+    Keep,
+    /// Any cell may have been changed.
+    Taint,
+}
+
+impl TryFrom<&str> for RawEffect {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(match value {
+            "keep" => Self::Keep,
+            "taint" => Self::Taint,
+
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Brainfuck injected as-is by `@raw "code" displacement effect`.
+#[derive(Debug)]
+pub struct Raw {
+    pub brainfuck: Vec<Brainfuck>,
+    pub displacement: isize,
+    pub effect: RawEffect,
 }
 
 #[derive(Debug)]
 pub struct Macro {
     pub parameter_count: usize,
+    pub exports: Vec<String>,
     pub instructions: Vec<Instruction>,
 }
 
+/// How many bits each cell of the target interpreter holds.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CellWidth {
+    Bits8,
+    Bits16,
+    Bits32,
+    Unbounded,
+}
+
+impl CellWidth {
+    /// The number of distinct values a cell can hold, if it is bounded at all.
+    pub fn modulus(self) -> Option<usize> {
+        match self {
+            Self::Bits8 => Some(1 << 8),
+            Self::Bits16 => Some(1 << 16),
+            Self::Bits32 => Some(1 << 32),
+            Self::Unbounded => None,
+        }
+    }
+}
+
+impl TryFrom<&str> for CellWidth {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(match value {
+            "8" => Self::Bits8,
+            "16" => Self::Bits16,
+            "32" => Self::Bits32,
+            "unbounded" => Self::Unbounded,
+
+            _ => return Err(()),
+        })
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum Brainfuck {
     Increment,
@@ -0,0 +1,263 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{HashMap, VecDeque};
+
+/// The span of instructions during which a group of reserved cells is in use.
+#[derive(Copy, Clone, Debug)]
+pub struct Lifetime {
+    /// The instruction offset that reserved the cells.
+    pub start: usize,
+    /// The instruction offset of the last access to the cells.
+    pub end: usize,
+    pub location: usize,
+    pub size: usize,
+    /// Whether the cells were fresh or known to be zero when they were reserved.
+    pub zeroed: bool,
+}
+
+/// Where a lifetime's new cells have to be cleared,
+/// because its variable relied on its old cells starting out zero.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Clear {
+    /// Where the cells are reserved.
+    OnReserve,
+    /// Just before the outermost loop around the reservation, which starts at this instruction
+    /// offset. Clearing them inside of the loop would clear them again on every iteration.
+    BeforeLoop(usize),
+}
+
+/// Where each lifetime's cells should be placed, by lifetime index.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    pub locations: Vec<usize>,
+    pub sizes: Vec<usize>,
+    pub clears: Vec<Option<Clear>>,
+}
+
+/// Assigns cells so that variables whose lifetimes never overlap share the same cells.
+///
+/// A loop may run its body any number of times, so anything used inside of a loop
+/// is kept alive for the whole loop, and has its cells to itself from the start of it.
+pub fn pack(lifetimes: &[Lifetime], loops: &[(usize, usize)]) -> Layout {
+    let mut spans: Vec<(usize, usize)> = lifetimes
+        .iter()
+        .map(|lifetime| (lifetime.start, lifetime.end))
+        .collect();
+
+    for span in &mut spans {
+        // Loops nest, so widening can only ever pull in loops that enclose the ones already seen.
+        let mut widened = true;
+
+        while widened {
+            widened = false;
+
+            for &(loop_start, loop_end) in loops {
+                let overlaps = loop_start <= span.1 && span.0 <= loop_end;
+                let covered = span.0 <= loop_start && loop_end <= span.1;
+
+                if overlaps && !covered {
+                    span.0 = span.0.min(loop_start);
+                    span.1 = span.1.max(loop_end);
+                    widened = true;
+                }
+            }
+        }
+    }
+
+    let mut order: Vec<usize> = (0..lifetimes.len()).collect();
+    order.sort_by_key(|&index| spans[index]);
+
+    // (location, size, end) of every placed lifetime that may still be live.
+    let mut active: Vec<(usize, usize, usize)> = Vec::new();
+    let mut locations = vec![0; lifetimes.len()];
+
+    for index in order {
+        let (start, end) = spans[index];
+        let size = lifetimes[index].size;
+
+        active.retain(|&(_, _, active_end)| active_end >= start);
+        active.sort_unstable();
+
+        // First fit: the lowest gap between live cells that is large enough.
+        let mut location = 0;
+
+        for &(active_location, active_size, _) in &active {
+            if location + size <= active_location {
+                break;
+            }
+
+            location = location.max(active_location + active_size);
+        }
+
+        locations[index] = location;
+        active.push((location, size, end));
+    }
+
+    let clears = lifetimes
+        .iter()
+        .map(|lifetime| {
+            let outermost = loops
+                .iter()
+                .filter(|&&(start, end)| start <= lifetime.start && lifetime.start <= end)
+                .map(|&(start, _)| start)
+                .min();
+
+            match outermost {
+                _ if !lifetime.zeroed => None,
+                Some(start) => Some(Clear::BeforeLoop(start)),
+                None => Some(Clear::OnReserve),
+            }
+        })
+        .collect();
+
+    Layout {
+        locations,
+        sizes: lifetimes.iter().map(|lifetime| lifetime.size).collect(),
+        clears,
+    }
+}
+
+/// Reorders cells so that cells accessed one after another end up next to each other.
+///
+/// The cells of a multi-cell variable are kept together and in order,
+/// and so is any run of cells shared by overlapping multi-cell variables.
+/// Every cell keeps its history, so the only clears needed are those of the layout
+/// the lifetimes were built with, if any.
+pub fn arrange(
+    lifetimes: &[Lifetime],
+    accesses: &[(usize, usize)],
+    previous: Option<&Layout>,
+) -> Layout {
+    let tape_length = lifetimes
+        .iter()
+        .map(|lifetime| lifetime.location + lifetime.size)
+        .max()
+        .unwrap_or(0);
+
+    // Start with one unit per cell, then join the cells of each multi-cell variable.
+    let mut joined = vec![false; tape_length];
+
+    for lifetime in lifetimes.iter().filter(|lifetime| lifetime.size > 1) {
+        joined[lifetime.location + 1..lifetime.location + lifetime.size].fill(true);
+    }
+
+    // (start, size) of every unit, in tape order.
+    let mut units: Vec<(usize, usize)> = Vec::new();
+    let mut unit_of = vec![0; tape_length];
+
+    for location in 0..tape_length {
+        if !joined[location] {
+            units.push((location, 0));
+        }
+
+        let unit = units.len() - 1;
+        units[unit].1 += 1;
+        unit_of[location] = unit;
+    }
+
+    // Weigh every pair of units by how often the head travels directly between them.
+    let mut trace: Vec<usize> = Vec::with_capacity(accesses.len());
+
+    for &(lifetime, offset) in accesses {
+        let unit = unit_of[lifetimes[lifetime].location + offset];
+
+        if trace.last() != Some(&unit) {
+            trace.push(unit);
+        }
+    }
+
+    let mut weights: HashMap<(usize, usize), usize> = HashMap::new();
+
+    for pair in trace.windows(2) {
+        let key = (pair[0].min(pair[1]), pair[0].max(pair[1]));
+        *weights.entry(key).or_default() += 1;
+    }
+
+    let weight = |a: usize, b: usize| weights.get(&(a.min(b), a.max(b))).copied().unwrap_or(0);
+
+    // Grow a chain outward from the first unit accessed,
+    // always attaching the unit most strongly tied to either end.
+    let mut first_seen: Vec<usize> = Vec::with_capacity(units.len());
+
+    for &unit in &trace {
+        if !first_seen.contains(&unit) {
+            first_seen.push(unit);
+        }
+    }
+
+    for unit in 0..units.len() {
+        if !first_seen.contains(&unit) {
+            first_seen.push(unit);
+        }
+    }
+
+    let mut chain = VecDeque::with_capacity(units.len());
+    let mut placed = vec![false; units.len()];
+
+    if let Some(&first) = first_seen.first() {
+        chain.push_back(first);
+        placed[first] = true;
+    }
+
+    while chain.len() < units.len() {
+        let (front, back) = (chain[0], chain[chain.len() - 1]);
+        let mut best: Option<(usize, bool, usize)> = None;
+
+        for &unit in first_seen.iter().filter(|&&unit| !placed[unit]) {
+            for (at_front, end) in [(false, back), (true, front)] {
+                let tie = weight(unit, end);
+
+                if best.is_none_or(|(best_tie, _, _)| tie > best_tie) {
+                    best = Some((tie, at_front, unit));
+                }
+            }
+        }
+
+        // There are still units left to place, so one of them was picked.
+        let (_, at_front, unit) = best.unwrap();
+
+        if at_front {
+            chain.push_front(unit);
+        } else {
+            chain.push_back(unit);
+        }
+
+        placed[unit] = true;
+    }
+
+    // The head starts at the leftmost cell, so the first unit used should be close to it.
+    if let Some(&first) = first_seen.first() {
+        let position = chain.iter().position(|&unit| unit == first).unwrap_or(0);
+
+        if position * 2 > chain.len() {
+            chain.make_contiguous().reverse();
+        }
+    }
+
+    let mut new_starts = vec![0; units.len()];
+    let mut next = 0;
+
+    for &unit in &chain {
+        new_starts[unit] = next;
+        next += units[unit].1;
+    }
+
+    Layout {
+        locations: lifetimes
+            .iter()
+            .map(|lifetime| {
+                let unit = unit_of[lifetime.location];
+                new_starts[unit] + lifetime.location - units[unit].0
+            })
+            .collect(),
+        sizes: lifetimes.iter().map(|lifetime| lifetime.size).collect(),
+        clears: match previous {
+            Some(layout) => layout.clears.clone(),
+            None => vec![None; lifetimes.len()],
+        },
+    }
+}
@@ -53,7 +53,8 @@ pub fn lex_file(filename: &str) -> Result<Vec<Token>, ()> {
                             Err(_) => panic!("WOO!"),
                         }
                     }
-                    '#' | '%' | _ if c.is_ascii_digit() => {
+                    // `#ff` is a hexadecimal literal and `%1010` a binary one.
+                    _ if c == '#' || c == '%' || c.is_ascii_digit() => {
                         let radix = match c {
                             '#' => 16,
                             '%' => 2,
@@ -79,10 +80,21 @@ pub fn lex_file(filename: &str) -> Result<Vec<Token>, ()> {
                     },
 
                     '@' => match lex_identifier(&mut iterator, None) {
-                        Ok(identifier) if identifier.len() > 0 => {
-                            match Directive::try_from(identifier.as_ref()) {
-                                Ok(directive) => Token::Directive(directive),
-                                Err(_) => panic!("WOO!"),
+                        Ok(identifier) if !identifier.is_empty() => {
+                            let (identifier, offset) = split_offset(&identifier);
+
+                            match Directive::try_from(identifier) {
+                                Ok(directive @ Directive::Parameter(_)) => {
+                                    tokens.push(Token::Directive(directive));
+
+                                    if let Some(offset) = offset {
+                                        tokens.push(Token::Offset(offset));
+                                    }
+
+                                    continue;
+                                }
+                                Ok(directive) if offset.is_none() => Token::Directive(directive),
+                                _ => panic!("WOO!"),
                             }
                         }
                         Ok(_) => panic!("WOO!"),
@@ -90,10 +102,21 @@ pub fn lex_file(filename: &str) -> Result<Vec<Token>, ()> {
                     },
 
                     _ if is_identifier_head(c) => match lex_identifier(&mut iterator, Some(c)) {
+                        Ok(identifier) if identifier.contains('.') => {
+                            let (identifier, offset) = split_offset(&identifier);
+
+                            tokens.push(Token::Identifier(identifier.to_owned()));
+
+                            if let Some(offset) = offset {
+                                tokens.push(Token::Offset(offset));
+                            }
+
+                            continue;
+                        }
                         Ok(identifier) => {
-                            if identifier.starts_with("__") {
-                                if identifier.len() > 2 {
-                                    match BuiltinReference::try_from(&identifier[2..]) {
+                            if let Some(builtin) = identifier.strip_prefix("__") {
+                                if !builtin.is_empty() {
+                                    match BuiltinReference::try_from(builtin) {
                                         Ok(builtin) => Token::Builtin(builtin),
                                         Err(_) => Token::Identifier(identifier),
                                     }
@@ -129,7 +152,7 @@ fn lex_integer<I>(iterator: &mut I, buffer: &mut String, radix: u32) -> Result<u
 where
     I: Iterator<Item = char>,
 {
-    while let Some(next) = iterator.next() {
+    for next in iterator.by_ref() {
         match next {
             '_' => {}
             _ if next.is_digit(radix) => buffer.push(next),
@@ -202,8 +225,8 @@ fn lex_identifier<I>(iterator: &mut I, head: Option<char>) -> Result<String, ()>
         identifier.push(c);
     }
 
-    while let Some(next) = iterator.next() {
-        if is_identifier_body(next) {
+    for next in iterator.by_ref() {
+        if is_identifier_body(next) || next == ':' || next == '.' {
             identifier.push(next);
         } else if is_token_break(next) {
             break;
@@ -212,9 +235,29 @@ fn lex_identifier<I>(iterator: &mut I, head: Option<char>) -> Result<String, ()>
         }
     }
 
+    // Qualified names are made of plain identifiers joined by `::`.
+    let name = identifier
+        .split_once('.')
+        .map_or(identifier.as_str(), |(name, _)| name);
+
+    if name.contains(':') && !name.split("::").all(is_plain_identifier) {
+        panic!("WOO: {}", identifier);
+    }
+
     Ok(identifier)
 }
 
+/// Splits a trailing cell offset, as in `buffer.3` or `@0.3`, off of an identifier.
+fn split_offset(identifier: &str) -> (&str, Option<usize>) {
+    match identifier.split_once('.') {
+        Some((name, offset)) => match offset.parse() {
+            Ok(offset) => (name, Some(offset)),
+            Err(_) => panic!("WOO: {}", identifier),
+        },
+        None => (identifier, None),
+    }
+}
+
 #[inline]
 fn is_identifier_head(c: char) -> bool {
     c.is_alphabetic() || c == '_'
@@ -225,6 +268,16 @@ fn is_identifier_body(c: char) -> bool {
     c.is_alphanumeric() || c == '_'
 }
 
+#[inline]
+fn is_plain_identifier(identifier: &str) -> bool {
+    let mut chars = identifier.chars();
+
+    match chars.next() {
+        Some(head) => is_identifier_head(head) && chars.all(is_identifier_body),
+        None => false,
+    }
+}
+
 #[inline]
 fn is_token_break(c: char) -> bool {
     c.is_whitespace()
@@ -3,3 +3,18 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
+
+use std::fmt::Display;
+
+/// Messages go to stderr so that they never mix with the output of a program being run.
+pub fn info(message: impl Display) {
+    eprintln!("{}", message);
+}
+
+pub fn error(message: impl Display) {
+    eprintln!("error: {}", message);
+}
+
+pub fn warning(message: impl Display) {
+    eprintln!("warning: {}", message);
+}
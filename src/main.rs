@@ -4,17 +4,222 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::time::Instant;
+
+use compiler::Options;
+use interpreter::{Eof, Machine};
+use language::Brainfuck;
+use language::CellWidth;
+use report::SizeOrder;
+
+mod c_backend;
 mod compiler;
+mod engine;
+mod interpreter;
+mod ir;
+mod knowledge;
 mod language;
+mod layout;
 mod lexer;
 mod logger;
+mod optimizer;
 mod parser;
+mod report;
 mod verifier;
 
 fn main() {
-    let tokens = lexer::lex_file("test.nou").unwrap();
+    let mut level = 2;
+    let mut pack_cells = false;
+    let mut arrange_cells = false;
+    let mut cell_width = CellWidth::Bits8;
+    let mut wrapping = true;
+    let mut tape_length = None;
+    let mut memory_map = None;
+    let mut size_report = None;
+    let mut size_order = SizeOrder::Total;
+    let mut eof = Eof::Zero;
+    let mut files = Vec::new();
+
+    let mut arguments = env::args().skip(1).peekable();
+
+    // `nou run file` compiles and runs a program in one step, or just runs a Brainfuck file.
+    // `nou bench file` does the same with both interpreters, comparing them.
+    let command = arguments.next_if(|argument| argument == "run" || argument == "bench");
+    let run = command.is_some();
+    let bench = command.as_deref() == Some("bench");
+    let mut naive = false;
+    let mut target_c = false;
+
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--cell-width" => {
+                let width = arguments.next().expect("WOO: --cell-width");
+                cell_width = CellWidth::try_from(width.as_str())
+                    .unwrap_or_else(|_| panic!("WOO: {}", width));
+            }
+            "--no-wrap" => wrapping = false,
+            "--tape-length" => {
+                let length = arguments.next().expect("WOO: --tape-length");
+                tape_length = Some(length.parse().unwrap_or_else(|_| panic!("WOO: {}", length)));
+            }
+            "--memory-map" => {
+                memory_map = Some(arguments.next().expect("WOO: --memory-map"));
+            }
+            "--size-report" => {
+                size_report = Some(arguments.next().expect("WOO: --size-report"));
+            }
+            "--sort-sizes" => {
+                let order = arguments.next().expect("WOO: --sort-sizes");
+                size_order = SizeOrder::try_from(order.as_str())
+                    .unwrap_or_else(|_| panic!("WOO: {}", order));
+            }
+            "--eof" => {
+                let behavior = arguments.next().expect("WOO: --eof");
+                eof = Eof::try_from(behavior.as_str())
+                    .unwrap_or_else(|_| panic!("WOO: {}", behavior));
+            }
+            "--target" => match arguments.next().as_deref() {
+                Some("bf") => target_c = false,
+                Some("c") => target_c = true,
+                target => panic!("WOO: {:?}", target),
+            },
+            "--naive" => naive = true,
+            "-O" => level = 3,
+            "-O0" => level = 0,
+            "-O1" => level = 1,
+            "-O2" => level = 2,
+            "-O3" => level = 3,
+            "--pack-cells" => pack_cells = true,
+            "--arrange-cells" => arrange_cells = true,
+            _ if argument.starts_with('-') => panic!("WOO: {}", argument),
+            _ => files.push(argument),
+        }
+    }
+
+    let input = files.first().map_or("test.nou", String::as_str);
+    let default_output = if target_c { "test.c" } else { "test.bf" };
+    let output = files.get(1).map_or(default_output, String::as_str);
+
+    let mut options = Options::level(level);
+
+    options.cell_width = cell_width;
+    options.wrapping = wrapping && cell_width != CellWidth::Unbounded;
+    options.tape_length = tape_length.unwrap_or(options.tape_length);
+    options.pack_cells |= pack_cells;
+    options.arrange_cells |= arrange_cells;
+
+    let machine = Machine {
+        cell_width: options.cell_width,
+        wrapping: options.wrapping,
+        tape_length: options.tape_length,
+        eof,
+    };
+
+    if run && input.ends_with(".bf") {
+        match fs::read_to_string(input) {
+            Ok(source) => execute(&interpreter::parse(&source), &machine, bench, naive),
+            Err(_) => panic!("WOO: {}", input),
+        }
+
+        return;
+    }
+
+    let tokens = lexer::lex_file(input).unwrap();
     let (top_level, macros) = parser::parse(tokens).unwrap();
-    compiler::Compiler::new()
-        .compile(top_level, macros, "test.bf")
-        .unwrap();
+    let compiler = compiler::Compiler::new(options);
+
+    let mut reports = if run {
+        let built = compiler.build(top_level, macros).unwrap();
+        execute(&built.brainfuck, &machine, bench, naive);
+        built.reports
+    } else if target_c {
+        let built = compiler.build(top_level, macros).unwrap();
+
+        if fs::write(output, c_backend::generate(&built.program, &machine)).is_err() {
+            panic!("WOO: {}", output)
+        }
+
+        built.reports
+    } else {
+        compiler.compile(top_level, macros, output).unwrap()
+    };
+
+    // The memory map is written as JSON when its file asks for it, and as a table otherwise.
+    if let Some(filename) = memory_map {
+        let contents = if filename.ends_with(".json") {
+            reports.memory_map.to_json()
+        } else {
+            reports.memory_map.to_text()
+        };
+
+        if fs::write(&filename, contents).is_err() {
+            panic!("WOO: {}", filename)
+        }
+    }
+
+    if let Some(filename) = size_report {
+        reports.sizes.sort(size_order);
+
+        if fs::write(&filename, reports.sizes.to_text()).is_err() {
+            panic!("WOO: {}", filename)
+        }
+    }
+}
+
+fn execute(brainfuck: &[Brainfuck], machine: &Machine, bench: bool, naive: bool) {
+    if bench {
+        return benchmark(brainfuck, machine);
+    }
+
+    let input = io::stdin().lock();
+    let output = BufWriter::new(io::stdout().lock());
+
+    let result = if naive {
+        interpreter::run(brainfuck, machine, input, output)
+    } else {
+        engine::Program::compile(brainfuck, machine)
+            .and_then(|program| program.run(machine, input, output))
+    };
+
+    if let Err(fault) = result {
+        panic!("WOO: {}", fault)
+    }
+}
+
+/// Runs a program with both interpreters on the same input, making sure they agree.
+fn benchmark(brainfuck: &[Brainfuck], machine: &Machine) {
+    let mut input = Vec::new();
+
+    if io::stdin().read_to_end(&mut input).is_err() {
+        panic!("WOO!")
+    }
+
+    let mut naive_output = Vec::new();
+    let start = Instant::now();
+    let naive_result = interpreter::run(brainfuck, machine, &input[..], &mut naive_output);
+    let naive_time = start.elapsed();
+
+    let mut fast_output = Vec::new();
+    let start = Instant::now();
+    let fast_result = engine::Program::compile(brainfuck, machine)
+        .and_then(|program| program.run(machine, &input[..], &mut fast_output));
+    let fast_time = start.elapsed();
+
+    if io::stdout().write_all(&fast_output).is_err() {
+        panic!("WOO!")
+    }
+
+    logger::info(format!("Naive interpreter: {:?}", naive_time));
+    logger::info(format!("Fast interpreter:  {:?}", fast_time));
+
+    if naive_output != fast_output || naive_result.is_ok() != fast_result.is_ok() {
+        panic!("WOO: the interpreters disagree")
+    }
+
+    if let Err(fault) = fast_result {
+        panic!("WOO: {}", fault)
+    }
 }
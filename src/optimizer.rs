@@ -0,0 +1,224 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::ir::Node;
+
+/// Removes nodes that can't change what the program reads or writes.
+///
+/// Each rewrite can expose another (a clear that loses what came before it may then follow a loop),
+/// so passes are made until one no longer changes the program.
+///
+/// Cells that don't wrap stop the program when they leave their range, so for them,
+/// changes to a cell are only merged or dropped where that can't skip over such a fault.
+pub fn optimize(mut program: Vec<Node>, wrapping: bool) -> Vec<Node> {
+    loop {
+        let optimized = peephole(&program, true, wrapping);
+
+        if optimized == program {
+            return fold_offsets(optimized);
+        }
+
+        program = optimized;
+    }
+}
+
+fn peephole(nodes: &[Node], top_level: bool, wrapping: bool) -> Vec<Node> {
+    let mut optimized: Vec<Node> = Vec::with_capacity(nodes.len());
+
+    for node in nodes {
+        match node {
+            Node::Loop(_) | Node::Clear | Node::MultiplyAdd { .. }
+                if is_zero(&optimized, top_level) => {}
+            Node::Loop(body) => optimized.push(Node::Loop(peephole(body, false, wrapping))),
+            Node::Clear => {
+                // Whatever the cell held before a clear no longer matters,
+                // unless getting it there could have faulted.
+                while wrapping && matches!(optimized.last(), Some(Node::Add { offset: 0, .. })) {
+                    optimized.pop();
+                }
+
+                if !is_zero(&optimized, top_level) {
+                    optimized.push(Node::Clear);
+                }
+            }
+            &Node::Add { offset, amount } => match optimized.last_mut() {
+                Some(Node::Add {
+                    offset: previous_offset,
+                    amount: previous,
+                }) if *previous_offset == offset
+                    && (wrapping || previous.signum() == amount.signum()) =>
+                {
+                    *previous += amount;
+
+                    if *previous == 0 {
+                        optimized.pop();
+                    }
+                }
+                _ => optimized.push(node.clone()),
+            },
+            &Node::Move(amount) => match optimized.last_mut() {
+                Some(Node::Move(previous)) => {
+                    *previous += amount;
+
+                    if *previous == 0 {
+                        optimized.pop();
+                    }
+                }
+                _ => optimized.push(node.clone()),
+            },
+            _ => optimized.push(node.clone()),
+        }
+    }
+
+    optimized
+}
+
+/// Turns a move there and back around an add into an add at an offset,
+/// which lowers to the same Brainfuck but doesn't move the head in between.
+/// Runs of moves have to have been merged already.
+fn fold_offsets(nodes: Vec<Node>) -> Vec<Node> {
+    let mut folded: Vec<Node> = Vec::with_capacity(nodes.len());
+
+    for node in nodes {
+        match node {
+            Node::Loop(body) => folded.push(Node::Loop(fold_offsets(body))),
+            Node::Move(back) => match folded[..] {
+                [.., Node::Move(there), Node::Add { offset: 0, amount }] if there == -back => {
+                    folded.truncate(folded.len() - 2);
+                    folded.push(Node::Add {
+                        offset: there,
+                        amount,
+                    });
+                }
+                _ => folded.push(node),
+            },
+            _ => folded.push(node),
+        }
+    }
+
+    folded
+}
+
+/// Whether the current cell is certainly zero after the given nodes: either nothing but moves
+/// has happened yet, or the last thing to happen only stops once the cell is zero.
+fn is_zero(before: &[Node], top_level: bool) -> bool {
+    match before.last() {
+        Some(Node::Loop(_) | Node::Clear | Node::MultiplyAdd { .. }) => true,
+        _ => top_level && before.iter().all(|node| matches!(node, Node::Move(_))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(amount: isize) -> Node {
+        Node::Add { offset: 0, amount }
+    }
+
+    #[test]
+    fn loops_on_zero_are_dropped() {
+        let multiply = Node::MultiplyAdd {
+            offset: 1,
+            factor: 2,
+        };
+
+        // The tape starts out zero, and every loop leaves its cell zero.
+        assert_eq!(
+            optimize(vec![Node::Move(1), Node::Clear, multiply.clone()], true),
+            [Node::Move(1)]
+        );
+        assert_eq!(
+            optimize(
+                vec![Node::Read, Node::Loop(vec![add(-1)]), Node::Clear],
+                true
+            ),
+            [Node::Read, Node::Loop(vec![add(-1)])]
+        );
+
+        // Inside of a loop, the current cell isn't zero to begin with.
+        let body = vec![Node::Loop(vec![Node::Move(1)]), Node::Move(1)];
+        let program = vec![Node::Read, Node::Loop(body)];
+        assert_eq!(optimize(program.clone(), true), program);
+    }
+
+    #[test]
+    fn adds_before_a_clear_are_dropped() {
+        assert_eq!(
+            optimize(vec![Node::Read, add(3), Node::Clear, add(1)], true),
+            [Node::Read, Node::Clear, add(1)]
+        );
+
+        // Each rewrite can expose another.
+        assert_eq!(optimize(vec![add(3), Node::Clear], true), []);
+    }
+
+    #[test]
+    fn adds_and_moves_are_merged() {
+        assert_eq!(
+            optimize(vec![add(2), add(3), Node::Move(2), Node::Move(-1)], true),
+            [add(5), Node::Move(1)]
+        );
+        assert_eq!(
+            optimize(
+                vec![Node::Read, add(2), add(-2), Node::Move(1), Node::Move(-1)],
+                true
+            ),
+            [Node::Read]
+        );
+    }
+
+    #[test]
+    fn adds_elsewhere_are_folded() {
+        let program = vec![
+            Node::Read,
+            Node::Move(2),
+            add(3),
+            Node::Move(-2),
+            Node::Loop(vec![Node::Move(-1), add(-1), Node::Move(1), add(-1)]),
+            Node::Move(1),
+            add(1),
+            Node::Move(1),
+        ];
+
+        assert_eq!(
+            optimize(program, true),
+            [
+                Node::Read,
+                Node::Add {
+                    offset: 2,
+                    amount: 3
+                },
+                Node::Loop(vec![
+                    Node::Add {
+                        offset: -1,
+                        amount: -1
+                    },
+                    add(-1)
+                ]),
+                Node::Move(1),
+                add(1),
+                Node::Move(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn faults_are_kept_without_wrapping() {
+        assert_eq!(
+            optimize(vec![Node::Read, add(3), Node::Clear], false),
+            [Node::Read, add(3), Node::Clear]
+        );
+        assert_eq!(
+            optimize(vec![Node::Read, add(2), add(-2)], false),
+            [Node::Read, add(2), add(-2)]
+        );
+        assert_eq!(
+            optimize(vec![Node::Read, add(2), add(3)], false),
+            [Node::Read, add(5)]
+        );
+    }
+}
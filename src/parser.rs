@@ -4,34 +4,76 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::iter::Peekable;
 
 use crate::language::*;
 
+/// A namespace block that is currently open, along with the imports made inside of it.
+struct Scope {
+    path: String,
+    /// Macros that can be called by their unqualified name.
+    imports: HashMap<String, String>,
+    /// Namespaces that can be named by their last part alone.
+    aliases: HashMap<String, String>,
+}
+
+/// The parameter count of every macro by qualified name, the path of every namespace,
+/// and the paths used in each scope, with the file scope first and namespace blocks in order.
+type Declarations = (HashMap<String, usize>, HashSet<String>, Vec<Vec<String>>);
+
 pub fn parse(tokens: Vec<Token>) -> Result<(Vec<Instruction>, HashMap<String, Macro>), ()> {
+    // Macros may be called before they are defined, so every signature is
+    // collected up front and the bodies are parsed in a second pass.
+    // The same goes for imports, which apply to their whole scope.
+    let (signatures, namespaces, uses) = match collect_signatures(&tokens) {
+        Ok(collected) => collected,
+        Err(_) => panic!("WOO!"),
+    };
+
     let mut current_macro = None;
+    let mut blocks: Vec<Vec<Instruction>> = Vec::new();
+    let mut scopes = vec![Scope {
+        path: String::new(),
+        imports: HashMap::new(),
+        aliases: HashMap::new(),
+    }];
+
+    // Namespace blocks are numbered in the order they are opened, the file scope being 0.
+    let mut namespace_count = 0;
+    import_all(&uses[0], &mut scopes, &signatures, &namespaces);
 
     let mut top_level = Vec::new();
     let mut macros: HashMap<String, Macro> = HashMap::new();
 
-    let mut iterator = tokens.into_iter();
+    let mut iterator = tokens.into_iter().peekable();
 
     while let Some(token) = iterator.next() {
         let instruction = match token {
+            Token::LeftBrace => {
+                blocks.push(Vec::new());
+                continue;
+            }
             Token::RightBrace => {
-                if let Some((macro_name, macro_data)) = current_macro.take() {
+                if let Some(block) = blocks.pop() {
+                    Instruction::Block(block)
+                } else if let Some((macro_name, macro_data)) = current_macro.take() {
                     macros.insert(macro_name, macro_data);
                     continue;
+                } else if scopes.len() > 1 {
+                    scopes.pop();
+                    continue;
                 } else {
                     panic!("WOO!")
                 }
             }
             Token::Identifier(identifier) => {
-                if let Some(macro_entry) = macros.get(&identifier) {
+                if let Some(macro_name) = resolve_macro(&identifier, &scopes, &signatures) {
+                    let parameter_count = signatures[&macro_name];
                     let mut values: [Value; 10] = Default::default();
-                    debug_assert!(macro_entry.parameter_count <= 10);
+                    debug_assert!(parameter_count <= 10);
 
-                    for i in 0..macro_entry.parameter_count {
+                    for i in 0..parameter_count {
                         match parse_value(&mut iterator) {
                             Ok(value) => unsafe {
                                 // SAFETY: The parameter_count is at most 10.
@@ -42,11 +84,11 @@ pub fn parse(tokens: Vec<Token>) -> Result<(Vec<Instruction>, HashMap<String, Ma
                     }
 
                     Instruction::Macro(
-                        identifier,
-                        ValueList {
-                            length: macro_entry.parameter_count,
+                        macro_name,
+                        Box::new(ValueList {
+                            length: parameter_count,
                             values,
-                        },
+                        }),
                     )
                 } else {
                     panic!("WOO: {}", identifier);
@@ -57,28 +99,79 @@ pub fn parse(tokens: Vec<Token>) -> Result<(Vec<Instruction>, HashMap<String, Ma
                 Err(_) => panic!("WOO!"),
             },
             Token::Directive(directive) => {
-                if current_macro.is_none() {
+                if let Directive::Raw = directive {
+                    match parse_raw(&mut iterator) {
+                        Ok(raw) => Instruction::Raw(raw),
+                        Err(_) => panic!("WOO!"),
+                    }
+                } else if !blocks.is_empty() {
+                    panic!("WOO!")
+                } else if let Some((_, macro_data)) = &mut current_macro {
                     match directive {
-                        Directive::Parameter(_) => panic!("WOO!"),
-                        Directive::Macro => match parse_macro(&mut iterator, &mut macros) {
-                            Ok(data) => {
-                                current_macro = Some(data);
+                        Directive::Export => match iterator.next() {
+                            Some(Token::Identifier(identifier)) => {
+                                macro_data.exports.push(identifier);
+                                continue;
+                            }
+                            _ => panic!("WOO!"),
+                        },
+                        _ => panic!("WOO!"),
+                    }
+                } else {
+                    // SAFETY: The file scope is never popped.
+                    let scope = unsafe { scopes.last_mut().unwrap_unchecked() };
+
+                    match directive {
+                        Directive::Parameter(_) | Directive::Export | Directive::Raw => {
+                            panic!("WOO!")
+                        }
+                        Directive::Macro => match parse_macro(&mut iterator) {
+                            Ok((macro_name, macro_data)) => {
+                                current_macro =
+                                    Some((qualify(&scope.path, &macro_name), macro_data));
+                                continue;
+                            }
+                            Err(_) => panic!("WOO!"),
+                        },
+                        Directive::Namespace => match parse_namespace(&mut iterator) {
+                            Ok(name) => {
+                                let path = qualify(&scope.path, &name);
+
+                                scopes.push(Scope {
+                                    path,
+                                    imports: HashMap::new(),
+                                    aliases: HashMap::new(),
+                                });
+
+                                namespace_count += 1;
+                                import_all(
+                                    &uses[namespace_count],
+                                    &mut scopes,
+                                    &signatures,
+                                    &namespaces,
+                                );
+
                                 continue;
                             }
                             Err(_) => panic!("WOO!"),
                         },
+                        // Already imported when its scope was opened.
+                        Directive::Use => match iterator.next() {
+                            Some(Token::Identifier(_)) => continue,
+                            _ => panic!("WOO!"),
+                        },
                         Directive::Include => {
                             panic!("WOO!")
                         }
                     }
-                } else {
-                    panic!("WOO!");
                 }
             }
             _ => panic!("WOO!"),
         };
 
-        if let Some((_, macro_data)) = &mut current_macro {
+        if let Some(block) = blocks.last_mut() {
+            block.push(instruction);
+        } else if let Some((_, macro_data)) = &mut current_macro {
             macro_data.instructions.push(instruction);
         } else {
             top_level.push(instruction);
@@ -87,12 +180,219 @@ pub fn parse(tokens: Vec<Token>) -> Result<(Vec<Instruction>, HashMap<String, Ma
 
     if let Some((macro_name, _)) = current_macro.take() {
         panic!("WOO: {}", macro_name)
+    } else if scopes.len() > 1 || !blocks.is_empty() {
+        panic!("WOO!")
     } else {
         Ok((top_level, macros))
     }
 }
 
-fn parse_macro<I>(iterator: &mut I, macros: &HashMap<String, Macro>) -> Result<(String, Macro), ()>
+/// Collects the fully qualified name and parameter count of every macro,
+/// along with the path of every namespace and the paths used in each scope.
+fn collect_signatures(tokens: &[Token]) -> Result<Declarations, ()> {
+    let mut signatures = HashMap::new();
+    let mut namespaces = HashSet::new();
+    let mut uses = vec![Vec::new()];
+
+    // One entry per open brace; namespaces contribute to the qualified path.
+    let mut blocks: Vec<Option<String>> = Vec::new();
+    let mut open_namespaces = Vec::new();
+    let mut iterator = tokens.iter();
+
+    while let Some(token) = iterator.next() {
+        match token {
+            Token::Directive(Directive::Macro) => {
+                let name = match iterator.next() {
+                    Some(Token::Identifier(identifier)) if !identifier.contains("::") => identifier,
+                    Some(token) => panic!("WOO: {:?}", token),
+                    None => panic!("WOO!"),
+                };
+
+                let parameter_count = match iterator.next() {
+                    Some(Token::Integer(integer)) if *integer <= 10 => *integer,
+                    _ => panic!("WOO!"),
+                };
+
+                let name = qualify(&block_path(&blocks), name);
+
+                if signatures.insert(name.clone(), parameter_count).is_some() {
+                    panic!("WOO: {}", name)
+                }
+            }
+            Token::Directive(Directive::Namespace) => {
+                let name = match iterator.next() {
+                    Some(Token::Identifier(identifier)) if !identifier.contains("::") => identifier,
+                    _ => panic!("WOO!"),
+                };
+
+                if let Some(Token::LeftBrace) = iterator.next() {
+                    let path = qualify(&block_path(&blocks), name);
+
+                    namespaces.insert(path.clone());
+                    blocks.push(Some(path));
+                    open_namespaces.push(uses.len());
+                    uses.push(Vec::new());
+                } else {
+                    panic!("WOO!")
+                }
+            }
+            Token::Directive(Directive::Use) => {
+                let path = match iterator.next() {
+                    Some(Token::Identifier(identifier)) => identifier,
+                    _ => panic!("WOO!"),
+                };
+
+                // Anywhere other than directly inside a namespace or the file is rejected later.
+                match blocks.last() {
+                    None => uses[0].push(path.clone()),
+                    Some(Some(_)) => uses[*open_namespaces.last().unwrap()].push(path.clone()),
+                    Some(None) => {}
+                }
+            }
+            Token::LeftBrace => blocks.push(None),
+            Token::RightBrace => {
+                if let Some(Some(_)) = blocks.pop() {
+                    open_namespaces.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok((signatures, namespaces, uses))
+}
+
+#[inline]
+fn block_path(blocks: &[Option<String>]) -> String {
+    blocks
+        .iter()
+        .rev()
+        .flatten()
+        .next()
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[inline]
+fn qualify(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}::{}", path, name)
+    }
+}
+
+/// Resolves the name used at a call site to the fully qualified name of a macro.
+///
+/// Enclosing namespaces are searched from the innermost outward;
+/// at each level the namespace's own macros win over its imports.
+fn resolve_macro(
+    identifier: &str,
+    scopes: &[Scope],
+    signatures: &HashMap<String, usize>,
+) -> Option<String> {
+    let (head, rest) = match identifier.split_once("::") {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (identifier, None),
+    };
+
+    for scope in scopes.iter().rev() {
+        let qualified = qualify(&scope.path, identifier);
+
+        if signatures.contains_key(&qualified) {
+            return Some(qualified);
+        }
+
+        let imported = match rest {
+            Some(rest) => scope.aliases.get(head).map(|target| qualify(target, rest)),
+            None => scope.imports.get(head).cloned(),
+        };
+
+        if let Some(imported) = imported.filter(|imported| signatures.contains_key(imported)) {
+            return Some(imported);
+        }
+    }
+
+    None
+}
+
+fn import_all(
+    paths: &[String],
+    scopes: &mut [Scope],
+    signatures: &HashMap<String, usize>,
+    namespaces: &HashSet<String>,
+) {
+    for path in paths {
+        if import(path, scopes, signatures, namespaces).is_err() {
+            panic!("WOO: {}", path)
+        }
+    }
+}
+
+/// Handles `@use path`.
+///
+/// Using a macro makes it callable by its unqualified name;
+/// using a namespace does the same for every macro directly inside of it,
+/// and lets the namespace itself be named by its last part.
+fn import(
+    path: &str,
+    scopes: &mut [Scope],
+    signatures: &HashMap<String, usize>,
+    namespaces: &HashSet<String>,
+) -> Result<(), ()> {
+    let target = scopes
+        .iter()
+        .rev()
+        .map(|scope| qualify(&scope.path, path))
+        .find(|target| signatures.contains_key(target) || namespaces.contains(target));
+
+    let target = match target {
+        Some(target) => target,
+        None => return Err(()),
+    };
+
+    // SAFETY: The file scope is never popped.
+    let scope = unsafe { scopes.last_mut().unwrap_unchecked() };
+
+    let imported: Vec<String> = if signatures.contains_key(&target) {
+        vec![target]
+    } else {
+        let members = signatures
+            .keys()
+            .filter(|name| {
+                name.strip_prefix(&target)
+                    .and_then(|rest| rest.strip_prefix("::"))
+                    .is_some_and(|rest| !rest.contains("::"))
+            })
+            .cloned()
+            .collect();
+
+        add_alias(&mut scope.aliases, target);
+        members
+    };
+
+    for name in imported {
+        add_alias(&mut scope.imports, name);
+    }
+
+    Ok(())
+}
+
+/// Makes a qualified name available by its last part, unless that already means something else.
+fn add_alias(aliases: &mut HashMap<String, String>, name: String) {
+    let alias = name
+        .rsplit_once("::")
+        .map_or(name.as_str(), |(_, alias)| alias);
+
+    match aliases.get(alias) {
+        Some(existing) if *existing != name => panic!("WOO: {}", alias),
+        _ => {
+            aliases.insert(alias.to_owned(), name);
+        }
+    }
+}
+
+fn parse_macro<I>(iterator: &mut I) -> Result<(String, Macro), ()>
 where
     I: Iterator<Item = Token>,
 {
@@ -106,61 +406,143 @@ fn parse_macro<I>(iterator: &mut I, macros: &HashMap<String, Macro>) -> Result<(
         panic!("WOO!")
     };
 
-    if macros.contains_key(&name) {
+    let parameter_count = if let Some(Token::Integer(integer)) = iterator.next() {
+        integer
+    } else {
         panic!("WOO!")
-    }
+    };
 
-    let parameter_count = if let Some(token) = iterator.next() {
-        if let Token::Integer(integer) = token {
-            integer
-        } else {
-            panic!("WOO!")
-        }
+    if let Some(Token::LeftBrace) = iterator.next() {
+        Ok((
+            name,
+            Macro {
+                parameter_count,
+                exports: Vec::new(),
+                instructions: Vec::new(),
+            },
+        ))
     } else {
         panic!("WOO!")
+    }
+}
+
+fn parse_namespace<I>(iterator: &mut I) -> Result<String, ()>
+where
+    I: Iterator<Item = Token>,
+{
+    let name = match iterator.next() {
+        Some(Token::Identifier(identifier)) if !identifier.contains("::") => identifier,
+        _ => panic!("WOO!"),
     };
 
-    if let Some(token) = iterator.next() {
-        if let Token::LeftBrace = token {
-            Ok((
-                name,
-                Macro {
-                    parameter_count,
-                    instructions: Vec::new(),
-                },
-            ))
-        } else {
-            panic!("WOO!")
-        }
+    if let Some(Token::LeftBrace) = iterator.next() {
+        Ok(name)
     } else {
         panic!("WOO!")
     }
 }
 
-fn parse_value<I>(iterator: &mut I) -> Result<Value, ()>
+fn parse_raw<I>(iterator: &mut Peekable<I>) -> Result<Raw, ()>
+where
+    I: Iterator<Item = Token>,
+{
+    let code = match iterator.next() {
+        Some(Token::String(string)) => string,
+        _ => panic!("WOO!"),
+    };
+
+    let displacement = match iterator.next() {
+        Some(Token::Integer(integer)) => integer as isize,
+        _ => panic!("WOO!"),
+    };
+
+    let effect = match iterator.next() {
+        Some(Token::Identifier(identifier)) => match RawEffect::try_from(identifier.as_ref()) {
+            Ok(effect) => effect,
+            Err(_) => panic!("WOO: {}", identifier),
+        },
+        _ => panic!("WOO!"),
+    };
+
+    let mut depth = 0usize;
+    let mut brainfuck = Vec::with_capacity(code.len());
+
+    for c in code.chars() {
+        let instruction = match c {
+            '+' => Brainfuck::Increment,
+            '-' => Brainfuck::Decrement,
+            '<' => Brainfuck::Left,
+            '>' => Brainfuck::Right,
+            ',' => Brainfuck::Read,
+            '.' => Brainfuck::Write,
+            '[' => {
+                depth += 1;
+                Brainfuck::IfZero
+            }
+            ']' => match depth.checked_sub(1) {
+                Some(remaining) => {
+                    depth = remaining;
+                    Brainfuck::IfNotZero
+                }
+                None => panic!("WOO: {}", code),
+            },
+            _ if c.is_whitespace() => continue,
+            _ => panic!("WOO: {}", code),
+        };
+
+        brainfuck.push(instruction);
+    }
+
+    if depth == 0 {
+        Ok(Raw {
+            brainfuck,
+            displacement,
+            effect,
+        })
+    } else {
+        panic!("WOO: {}", code)
+    }
+}
+
+fn parse_value<I>(iterator: &mut Peekable<I>) -> Result<Value, ()>
 where
     I: Iterator<Item = Token>,
 {
-    if let Some(token) = iterator.next() {
-        Ok(match token {
+    let value = if let Some(token) = iterator.next() {
+        match token {
             Token::Integer(integer) => Value::Literal(integer),
             Token::Identifier(identifier) => Value::Variable(identifier),
             Token::Directive(Directive::Parameter(parameter)) => Value::Parameter(parameter),
             _ => panic!("WOO!"),
-        })
+        }
     } else {
         panic!("WOO!")
+    };
+
+    if let Some(&Token::Offset(offset)) = iterator.peek() {
+        iterator.next();
+
+        if let Value::Literal(_) = value {
+            panic!("WOO!")
+        } else {
+            Ok(Value::Offset(Box::new(value), offset))
+        }
+    } else {
+        Ok(value)
     }
 }
 
-fn parse_builtin<I>(iterator: &mut I, builtin_ref: BuiltinReference) -> Result<Builtin, ()>
+fn parse_builtin<I>(
+    iterator: &mut Peekable<I>,
+    builtin_ref: BuiltinReference,
+) -> Result<Builtin, ()>
 where
     I: Iterator<Item = Token>,
 {
     Ok(match builtin_ref {
         BuiltinReference::Allocate => match parse_value(iterator) {
             Ok(value) => {
-                if let Value::Literal(_) = value {
+                if let Value::Literal(_) | Value::Offset(..) = value {
                     panic!("WOO!")
                 } else {
                     Builtin::Allocate(value)
@@ -168,9 +550,19 @@ fn parse_builtin<I>(iterator: &mut I, builtin_ref: BuiltinReference) -> Result<B
             }
             Err(_) => panic!("WOO!"),
         },
+        BuiltinReference::Free => match parse_value(iterator) {
+            Ok(value) => {
+                if let Value::Literal(_) | Value::Offset(..) = value {
+                    panic!("WOO!")
+                } else {
+                    Builtin::Free(value)
+                }
+            }
+            Err(_) => panic!("WOO!"),
+        },
         BuiltinReference::Reserve => match parse_value(iterator) {
             Ok(value) => {
-                if let Value::Variable(_) = value {
+                if let Value::Variable(_) | Value::Offset(..) = value {
                     panic!("WOO!")
                 } else {
                     Builtin::Reserve(value)
@@ -180,7 +572,7 @@ fn parse_builtin<I>(iterator: &mut I, builtin_ref: BuiltinReference) -> Result<B
         },
         BuiltinReference::Set => match parse_value(iterator) {
             Ok(value) => {
-                if let Value::Variable(_) = value {
+                if let Value::Variable(_) | Value::Offset(..) = value {
                     panic!("WOO!")
                 } else {
                     Builtin::Set(value)
@@ -201,7 +593,7 @@ fn parse_builtin<I>(iterator: &mut I, builtin_ref: BuiltinReference) -> Result<B
 
         BuiltinReference::Add => match parse_value(iterator) {
             Ok(value) => {
-                if let Value::Variable(_) = value {
+                if let Value::Variable(_) | Value::Offset(..) = value {
                     panic!("WOO!")
                 } else {
                     Builtin::Add(value)
@@ -211,7 +603,7 @@ fn parse_builtin<I>(iterator: &mut I, builtin_ref: BuiltinReference) -> Result<B
         },
         BuiltinReference::Subtract => match parse_value(iterator) {
             Ok(value) => {
-                if let Value::Variable(_) = value {
+                if let Value::Variable(_) | Value::Offset(..) = value {
                     panic!("WOO!")
                 } else {
                     Builtin::Subtract(value)
@@ -221,7 +613,7 @@ fn parse_builtin<I>(iterator: &mut I, builtin_ref: BuiltinReference) -> Result<B
         },
         BuiltinReference::Left => match parse_value(iterator) {
             Ok(value) => {
-                if let Value::Variable(_) = value {
+                if let Value::Variable(_) | Value::Offset(..) = value {
                     panic!("WOO!")
                 } else {
                     Builtin::Left(value)
@@ -231,7 +623,7 @@ fn parse_builtin<I>(iterator: &mut I, builtin_ref: BuiltinReference) -> Result<B
         },
         BuiltinReference::Right => match parse_value(iterator) {
             Ok(value) => {
-                if let Value::Variable(_) = value {
+                if let Value::Variable(_) | Value::Offset(..) = value {
                     panic!("WOO!")
                 } else {
                     Builtin::Right(value)
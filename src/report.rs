@@ -0,0 +1,239 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::cmp::Reverse;
+use std::fmt::Write;
+use std::iter;
+
+use crate::layout::Lifetime;
+
+/// Everything the compiler can report about a program besides the program itself.
+#[derive(Clone, Debug, Default)]
+pub struct Reports {
+    pub memory_map: MemoryMap,
+    pub sizes: SizeReport,
+}
+
+/// A variable that was allocated at some point during compilation.
+#[derive(Clone, Debug)]
+pub struct MemoryEntry {
+    /// The variable's full name, including the expansion that owns it.
+    pub name: String,
+    /// The chain of macro expansions that allocated it, outermost first.
+    pub scope: String,
+    /// Where its cells ended up and when they were in use, if any were ever reserved for it.
+    pub lifetime: Option<Lifetime>,
+}
+
+/// Every variable of a program and the cells it was given.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryMap {
+    pub entries: Vec<MemoryEntry>,
+}
+
+impl MemoryMap {
+    pub fn to_text(&self) -> String {
+        let rows: Vec<[String; 4]> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let (cells, instructions) = match &entry.lifetime {
+                    Some(lifetime) => (
+                        format!(
+                            "{}..{}",
+                            lifetime.location,
+                            lifetime.location + lifetime.size
+                        ),
+                        format!("{}..={}", lifetime.start, lifetime.end),
+                    ),
+                    None => ("-".to_owned(), "-".to_owned()),
+                };
+
+                [cells, entry.name.clone(), entry.scope.clone(), instructions]
+            })
+            .collect();
+
+        let header = ["cells", "name", "scope", "instructions"].map(str::to_owned);
+        let mut widths = header.clone().map(|column| column.len());
+
+        for row in &rows {
+            for (width, column) in widths.iter_mut().zip(row) {
+                *width = (*width).max(column.len());
+            }
+        }
+
+        let mut text = String::new();
+
+        for row in iter::once(&header).chain(&rows) {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(column, width)| format!("{:width$}", column, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+
+            let _ = writeln!(text, "{}", line.trim_end());
+        }
+
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> =
+            self.entries
+                .iter()
+                .map(|entry| {
+                    let placement = match &entry.lifetime {
+                        Some(lifetime) => format!(
+                            "\"location\": {}, \"size\": {}, \"start\": {}, \"end\": {}",
+                            lifetime.location, lifetime.size, lifetime.start, lifetime.end
+                        ),
+                        None => "\"location\": null, \"size\": 0, \"start\": null, \"end\": null"
+                            .to_owned(),
+                    };
+
+                    format!(
+                        "    {{\"name\": {}, \"scope\": {}, {}}}",
+                        json_string(&entry.name),
+                        json_string(&entry.scope),
+                        placement
+                    )
+                })
+                .collect();
+
+        if entries.is_empty() {
+            "[]\n".to_owned()
+        } else {
+            format!("[\n{}\n]\n", entries.join(",\n"))
+        }
+    }
+}
+
+/// How much output a macro is responsible for, before the peephole optimizer runs.
+#[derive(Clone, Debug, Default)]
+pub struct SizeEntry {
+    pub name: String,
+    /// How many times the macro was expanded.
+    pub calls: usize,
+    /// Instructions emitted while the macro was expanding, including by the macros it called.
+    pub total: usize,
+    /// Instructions emitted by the macro's own builtins.
+    pub own: usize,
+}
+
+/// What to sort a size report by. Sizes and calls are sorted largest first.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SizeOrder {
+    Total,
+    Own,
+    Calls,
+    PerCall,
+    Name,
+}
+
+impl TryFrom<&str> for SizeOrder {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(match value {
+            "total" => Self::Total,
+            "own" => Self::Own,
+            "calls" => Self::Calls,
+            "per-call" => Self::PerCall,
+            "name" => Self::Name,
+
+            _ => return Err(()),
+        })
+    }
+}
+
+/// The output size of a whole program, broken down by the macros that produced it.
+#[derive(Clone, Debug, Default)]
+pub struct SizeReport {
+    /// Every instruction of the program, before the peephole optimizer runs.
+    pub total: usize,
+    /// Instructions emitted outside of any macro.
+    pub top_level: usize,
+    pub entries: Vec<SizeEntry>,
+}
+
+impl SizeReport {
+    pub fn sort(&mut self, order: SizeOrder) {
+        match order {
+            SizeOrder::Total => self.entries.sort_by_key(|entry| Reverse(entry.total)),
+            SizeOrder::Own => self.entries.sort_by_key(|entry| Reverse(entry.own)),
+            SizeOrder::Calls => self.entries.sort_by_key(|entry| Reverse(entry.calls)),
+            SizeOrder::PerCall => self
+                .entries
+                .sort_by_key(|entry| Reverse(entry.total / entry.calls.max(1))),
+            SizeOrder::Name => self.entries.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        let _ = writeln!(text, "total size: {}", self.total);
+        let _ = writeln!(text, "top level:  {}", self.top_level);
+        let _ = writeln!(text);
+
+        let name_width = self
+            .entries
+            .iter()
+            .map(|entry| entry.name.len())
+            .max()
+            .unwrap_or(0)
+            .max("macro".len());
+
+        let _ = writeln!(
+            text,
+            "{:name_width$}  {:>8}  {:>8}  {:>8}  {:>8}",
+            "macro",
+            "calls",
+            "total",
+            "own",
+            "per call",
+            name_width = name_width
+        );
+
+        for entry in &self.entries {
+            let _ = writeln!(
+                text,
+                "{:name_width$}  {:>8}  {:>8}  {:>8}  {:>8}",
+                entry.name,
+                entry.calls,
+                entry.total,
+                entry.own,
+                entry.total / entry.calls.max(1),
+                name_width = name_width
+            );
+        }
+
+        text
+    }
+}
+
+/// Quotes a string for JSON, escaping anything that can't appear in it as is.
+fn json_string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
@@ -3,3 +3,152 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
+
+use std::fmt;
+
+use crate::language::Brainfuck;
+
+/// Something wrong with a finished Brainfuck program, by index into its instructions.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Diagnostic {
+    UnmatchedOpen(usize),
+    UnmatchedClose(usize),
+    /// The head would move to the left of the first cell.
+    BelowTape(usize),
+    /// The head would move past the last cell of the tape.
+    PastTape(usize),
+    /// A loop whose body leaves the head somewhere other than where it started.
+    /// Scan loops such as `[>]` from `@raw` are meant to, so this is only a warning.
+    UnbalancedLoop {
+        start: usize,
+        end: usize,
+        displacement: isize,
+    },
+}
+
+impl Diagnostic {
+    /// Whether the program is certain to go wrong, rather than only possibly.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Self::UnbalancedLoop { .. })
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnmatchedOpen(index) => write!(f, "`[` at {} is never closed", index),
+            Self::UnmatchedClose(index) => write!(f, "`]` at {} closes nothing", index),
+            Self::BelowTape(index) => write!(f, "`<` at {} moves left of the first cell", index),
+            Self::PastTape(index) => write!(f, "`>` at {} moves past the end of the tape", index),
+            Self::UnbalancedLoop {
+                start,
+                end,
+                displacement,
+            } => write!(
+                f,
+                "loop from {} to {} moves the head by {} cells",
+                start, end, displacement
+            ),
+        }
+    }
+}
+
+/// Checks a program for anything that would make it go wrong on a tape of `tape_length` cells.
+///
+/// The head is only followed while its position is known,
+/// which stops being the case after a loop that doesn't end where it started.
+pub fn verify(brainfuck: &[Brainfuck], tape_length: usize) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    // The head relative to where it started, and whether that is still where it really is.
+    let mut offset: isize = 0;
+    let mut known = true;
+    let mut open = Vec::new();
+
+    for (index, instruction) in brainfuck.iter().enumerate() {
+        match instruction {
+            Brainfuck::Left => {
+                offset -= 1;
+
+                if known && offset < 0 {
+                    diagnostics.push(Diagnostic::BelowTape(index));
+                    known = false;
+                }
+            }
+            Brainfuck::Right => {
+                offset += 1;
+
+                if known && offset as usize >= tape_length {
+                    diagnostics.push(Diagnostic::PastTape(index));
+                    known = false;
+                }
+            }
+            Brainfuck::IfZero => open.push((index, offset)),
+            Brainfuck::IfNotZero => match open.pop() {
+                Some((start, entry)) if entry != offset => {
+                    diagnostics.push(Diagnostic::UnbalancedLoop {
+                        start,
+                        end: index,
+                        displacement: offset - entry,
+                    });
+                    known = false;
+                }
+                Some(_) => {}
+                None => diagnostics.push(Diagnostic::UnmatchedClose(index)),
+            },
+            _ => {}
+        }
+    }
+
+    for (start, _) in open {
+        diagnostics.push(Diagnostic::UnmatchedOpen(start));
+    }
+
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::parse;
+
+    fn check(source: &str, tape_length: usize) -> Vec<Diagnostic> {
+        verify(&parse(source), tape_length)
+    }
+
+    #[test]
+    fn balanced_program() {
+        assert_eq!(check("+[->+<]>>.<<", 3), []);
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        assert_eq!(check("+[[-]", 1), [Diagnostic::UnmatchedOpen(1)]);
+        assert_eq!(check("[-]]", 1), [Diagnostic::UnmatchedClose(3)]);
+        assert!(Diagnostic::UnmatchedOpen(0).is_fatal());
+        assert!(Diagnostic::UnmatchedClose(0).is_fatal());
+    }
+
+    #[test]
+    fn head_leaves_tape() {
+        assert_eq!(check("><<", 2), [Diagnostic::BelowTape(2)]);
+        assert_eq!(check(">>", 2), [Diagnostic::PastTape(1)]);
+        assert!(Diagnostic::BelowTape(0).is_fatal());
+        assert!(Diagnostic::PastTape(0).is_fatal());
+    }
+
+    #[test]
+    fn unbalanced_loop() {
+        let unbalanced = Diagnostic::UnbalancedLoop {
+            start: 1,
+            end: 3,
+            displacement: 1,
+        };
+
+        assert_eq!(check("+[>]", 4), [unbalanced]);
+        assert!(!unbalanced.is_fatal());
+
+        // Where the head is after the loop isn't known, so nothing past it is reported.
+        assert_eq!(check("+[>]<<<<", 4), [unbalanced]);
+    }
+}
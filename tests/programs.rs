@@ -0,0 +1,365 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::env;
+use std::fs;
+use std::process::{Command, Output};
+
+const STD: &str = include_str!("../std/std.nou");
+
+/// Compiles and runs a program that uses the standard library.
+fn execute(name: &str, program: &str, level: &str) -> Output {
+    execute_with(name, program, &[level])
+}
+
+/// Compiles and runs a program that uses the standard library, passing the given flags.
+fn execute_with(name: &str, program: &str, flags: &[&str]) -> Output {
+    let path = env::temp_dir().join(format!("nou-{}-{}.nou", name, std::process::id()));
+    fs::write(&path, format!("{}\n{}", STD, program)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nou"))
+        .arg("run")
+        .args(flags)
+        .arg(&path)
+        .output()
+        .unwrap();
+
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+/// Runs a program that has to compile, returning what it printed.
+fn run(name: &str, program: &str, level: &str) -> Vec<u8> {
+    run_with(name, program, &[level])
+}
+
+/// Runs a program that has to compile with the given flags, returning what it printed.
+fn run_with(name: &str, program: &str, flags: &[&str]) -> Vec<u8> {
+    let output = execute_with(name, program, flags);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    output.stdout
+}
+
+/// Every optimization level has to print exactly what the unoptimized program prints.
+fn assert_levels_agree(name: &str, program: &str) {
+    let expected = run(name, program, "-O0");
+
+    for level in ["-O1", "-O2", "-O3"] {
+        assert_eq!(run(name, program, level), expected, "{} at {}", name, level);
+    }
+}
+
+/// Laying out cells, alone or together, must not change what the unoptimized program prints.
+fn assert_layouts_agree(name: &str, program: &str) {
+    let expected = run(name, program, "-O0");
+
+    for flags in [
+        &["-O0", "--pack-cells"][..],
+        &["-O0", "--arrange-cells"],
+        &["-O0", "--pack-cells", "--arrange-cells"],
+        &["-O2", "--pack-cells"],
+        &["-O2", "--arrange-cells"],
+    ] {
+        assert_eq!(
+            run_with(name, program, flags),
+            expected,
+            "{} with {:?}",
+            name,
+            flags
+        );
+    }
+}
+
+#[test]
+fn value_changed_in_loop_before_set() {
+    let program = "
+new x 0
+new c 3
+start_loop c
+inc x
+set x 65
+end_loop_dec c
+emit x
+";
+
+    assert_eq!(run("loop-set", program, "-O0"), b"A");
+    assert_levels_agree("loop-set", program);
+}
+
+#[test]
+fn raw_scan_loop() {
+    let program = "
+new a 1
+new b 1
+new z 0
+new r 66
+__move a
+@raw \"[>]\" 2 keep
+emit r
+";
+
+    assert_eq!(run("raw-scan", program, "-O0"), b"B");
+    assert_levels_agree("raw-scan", program);
+}
+
+#[test]
+fn use_before_definition() {
+    let program = "
+new b 0
+hi b
+emit b
+@use ns
+@use outer::inner
+inner::bump b
+emit b
+@namespace ns {
+    @macro hi 1 {
+        __move @0
+        __add 65
+    }
+}
+@namespace outer {
+    @namespace inner {
+        @macro bump 1 {
+            hi @0
+            __move @0
+            __sub 64
+        }
+        @use ns
+    }
+}
+";
+
+    assert_eq!(run("use", program, "-O2"), b"AB");
+}
+
+#[test]
+fn macros_only_see_file_level_variables() {
+    let program = "
+@macro show 0 {
+    emit v
+}
+new v 66
+show
+";
+
+    assert_eq!(run("file-level", program, "-O2"), b"B");
+
+    let program = "
+@macro show 0 {
+    emit v
+}
+{
+    new v 65
+    show
+}
+";
+
+    assert!(!execute("block-level", program, "-O2").status.success());
+}
+
+#[test]
+fn hexadecimal_and_binary_literals() {
+    let program = "
+new a #41
+new b %100_0010
+emit a
+emit b
+";
+
+    assert_eq!(run("literals", program, "-O2"), b"AB");
+}
+
+#[test]
+fn allocation_inside_loop() {
+    let program = "
+new n 3
+start_loop n
+allocate b
+inc b
+end_loop_dec n
+emit b
+";
+
+    assert_eq!(run("alloc-loop", program, "-O0"), [3]);
+    assert_levels_agree("alloc-loop", program);
+    assert_layouts_agree("alloc-loop", program);
+}
+
+#[test]
+fn allocation_inside_skipped_conditional() {
+    let program = "
+new c 0
+fetch c
+new a 5
+emit a
+start_if c
+new b 7
+set c 0
+end_if c
+emit b
+";
+
+    assert_eq!(run("alloc-if", program, "-O0"), [5, 0]);
+    assert_levels_agree("alloc-if", program);
+    assert_layouts_agree("alloc-if", program);
+}
+
+#[test]
+fn allocation_inside_blocks() {
+    let program = "
+new total 60
+{
+    new a 2
+    add total a
+}
+{
+    new b 1
+    add total b
+    {
+        new c 2
+        add total c
+    }
+}
+emit total
+";
+
+    assert_eq!(run("alloc-block", program, "-O0"), b"A");
+    assert_levels_agree("alloc-block", program);
+    assert_layouts_agree("alloc-block", program);
+}
+
+#[test]
+fn allocation_after_free() {
+    let program = "
+new a 70
+emit a
+__free a
+new b 1
+emit b
+new n 2
+start_loop n
+new t 9
+__free t
+new u 1
+inc u
+emit u
+end_loop_dec n
+";
+
+    assert_eq!(run("alloc-free", program, "-O0"), [70, 1, 2, 2]);
+    assert_levels_agree("alloc-free", program);
+    assert_layouts_agree("alloc-free", program);
+}
+
+#[test]
+fn markers_have_to_balance() {
+    let program = "
+new a 65
+__mark
+new b 1
+add a b
+__restore
+new c 1
+add a c
+emit a
+";
+
+    assert_eq!(run("markers", program, "-O2"), b"C");
+
+    for (name, program) in [
+        ("restore-block", "{\n__restore\n}\n"),
+        ("mark-block", "{\n__mark\n}\n"),
+        ("mark-macro", "@macro m 0 {\n__mark\n}\nm\n__restore\n"),
+        ("restore-macro", "@macro r 0 {\n__restore\n}\n__mark\nr\n"),
+        ("mark-top-level", "__mark\n"),
+    ] {
+        assert!(!execute(name, program, "-O2").status.success(), "{}", name);
+    }
+}
+
+#[test]
+fn unbalanced_loop() {
+    let program = "
+new a 1
+__move a
+__ifz
+__move 3
+__ifnz
+";
+
+    let output = execute("unbalanced", program, "-O2");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("starts on cell 0 but ends on cell 3"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn tape_length_is_checked_after_packing() {
+    let program = "
+new a 65
+emit a
+new b 66
+emit b
+new c 67
+emit c
+";
+
+    let flags = ["-O0", "--tape-length", "2"];
+    assert!(!execute_with("tape", program, &flags).status.success());
+
+    let flags = ["-O0", "--pack-cells", "--tape-length", "2"];
+    assert_eq!(run_with("tape", program, &flags), b"ABC");
+}
+
+#[test]
+fn moves_are_charged_to_the_macro_that_made_them() {
+    let program = "
+@macro go 1 {
+    __move @0
+}
+@macro skipped 0 {
+    __add 1
+}
+allocate x
+allocate y
+allocate z
+go z
+__add 1
+__move x
+__ifz
+skipped
+__ifnz
+";
+
+    let report = env::temp_dir().join(format!("nou-sizes-{}.txt", std::process::id()));
+    let flags = ["-O2", "--size-report", report.to_str().unwrap()];
+    run_with("sizes", program, &flags);
+
+    let text = fs::read_to_string(&report).unwrap();
+    fs::remove_file(&report).unwrap();
+
+    // The move to `z` happens in `go`, even though it's only emitted for the `__add` after it,
+    // and `skipped` is inside of a loop that never runs.
+    assert!(text.contains("top level:  1\n"), "{}", text);
+    assert!(
+        text.lines()
+            .any(|line| line.split_whitespace().eq(["go", "1", "2", "2", "2"])),
+        "{}",
+        text
+    );
+    assert!(!text.contains("skipped"), "{}", text);
+}